@@ -1,5 +1,5 @@
 use std::io::Result;
 fn main() -> Result<()> {
-    prost_build::compile_protos(&["src/Mumble.proto"], &["src/"])?;
+    prost_build::compile_protos(&["src/Mumble.proto", "src/MumbleUDP.proto"], &["src/"])?;
     Ok(())
 }
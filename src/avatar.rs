@@ -0,0 +1,207 @@
+use std::{io::Cursor, time::Duration};
+
+use image::{codecs::jpeg::JpegEncoder, imageops::FilterType};
+use log::{debug, warn};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::{mumble_proto, state::ServerLimits, types::MumbleMsg};
+
+/// Edge lengths to try scaling the avatar down to, largest first.
+const SIZES: [u32; 6] = [256, 192, 128, 96, 64, 32];
+
+/// JPEG qualities to try at each size.
+const QUALITIES: [u8; 3] = [85, 70, 50];
+
+/// How long downloading an image may take.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Largest image we download and decode.
+const MAX_IMAGE_SIZE: usize = 8 * 1024 * 1024;
+
+async fn fetch(url: &str) -> anyhow::Result<Vec<u8>> {
+    let client = reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?;
+    let mut response = client.get(url).send().await?.error_for_status()?;
+
+    if response
+        .content_length()
+        .is_some_and(|len| len > MAX_IMAGE_SIZE as u64)
+    {
+        anyhow::bail!("image is larger than {} bytes", MAX_IMAGE_SIZE);
+    }
+
+    // The announced length may be missing or wrong, so count as we go.
+    let mut data = vec![];
+    while let Some(chunk) = response.chunk().await? {
+        if data.len() + chunk.len() > MAX_IMAGE_SIZE {
+            anyhow::bail!("image is larger than {} bytes", MAX_IMAGE_SIZE);
+        }
+        data.extend_from_slice(&chunk);
+    }
+
+    Ok(data)
+}
+
+/**
+ * Scale an image down and recompress it as JPEG until it fits in `max_len`
+ * bytes, the largest texture the server accepts.
+ */
+fn fit(data: &[u8], max_len: Option<u32>) -> anyhow::Result<Vec<u8>> {
+    let image = image::load_from_memory(data)?;
+
+    for size in SIZES {
+        let scaled = image.resize(size, size, FilterType::Lanczos3).to_rgb8();
+
+        for quality in QUALITIES {
+            let mut output = Cursor::new(Vec::new());
+            scaled.write_with_encoder(JpegEncoder::new_with_quality(&mut output, quality))?;
+            let output = output.into_inner();
+
+            if max_len.is_none_or(|max_len| output.len() <= max_len as usize) {
+                debug!(
+                    "Avatar scaled to {}x{} at quality {}, {} bytes",
+                    scaled.width(),
+                    scaled.height(),
+                    quality,
+                    output.len()
+                );
+                return Ok(output);
+            }
+        }
+    }
+
+    anyhow::bail!("image does not fit in {:?} bytes", max_len)
+}
+
+/**
+ * Load the image at `url`, or the default avatar if there is none, as a texture
+ * the server accepts. An empty texture clears our avatar.
+ */
+async fn load(
+    url: Option<&str>,
+    default_file: Option<&str>,
+    max_len: Option<u32>,
+) -> anyhow::Result<Vec<u8>> {
+    let data = match (url, default_file) {
+        (Some(url), _) => fetch(url).await?,
+        (None, Some(default_file)) => tokio::fs::read(default_file).await?,
+        (None, None) => return Ok(vec![]),
+    };
+
+    tokio::task::spawn_blocking(move || fit(&data, max_len)).await?
+}
+
+/**
+ * Our avatar, showing the cover art of the current song.
+ */
+pub struct Avatar {
+    default_file: Option<String>,
+    /// The image we last set: a URL, or None for the default.
+    shown: Option<Option<String>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl Avatar {
+    pub fn new(default_file: Option<String>) -> Self {
+        Avatar {
+            default_file,
+            shown: None,
+            task: None,
+        }
+    }
+
+    /**
+     * Set the avatar again on the next `show`, e.g. after reconnecting.
+     */
+    pub fn invalidate(&mut self) {
+        self.shown = None;
+    }
+
+    /**
+     * Show the image at `url`, or the default avatar if None. Loading happens in
+     * the background, and is abandoned if another image is shown in the meantime.
+     */
+    pub fn show(
+        &mut self,
+        url: Option<&str>,
+        msg_sender: &mpsc::Sender<MumbleMsg>,
+        limits: &ServerLimits,
+    ) {
+        let url = url.map(str::to_string);
+        if self.shown.as_ref() == Some(&url) {
+            return;
+        }
+
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+
+        self.shown = Some(url.clone());
+
+        let default_file = self.default_file.clone();
+        let max_len = limits.image_message_length;
+        let msg_sender = msg_sender.clone();
+
+        self.task = Some(tokio::spawn(async move {
+            let texture = match load(url.as_deref(), default_file.as_deref(), max_len).await {
+                Ok(texture) => texture,
+                Err(e) => {
+                    warn!("Failed to load avatar {:?}: {:?}", url, e);
+                    load(None, default_file.as_deref(), max_len)
+                        .await
+                        .unwrap_or_default()
+                }
+            };
+
+            let msg = mumble_proto::UserState {
+                texture: Some(texture),
+                ..Default::default()
+            };
+            let _ = msg_sender.send(MumbleMsg::UserState(msg)).await;
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageFormat, RgbImage};
+
+    use super::*;
+
+    /**
+     * A large PNG full of noise, which doesn't compress well.
+     */
+    fn large_png() -> Vec<u8> {
+        let mut seed = 1u32;
+        let image = RgbImage::from_fn(640, 480, |_, _| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let [r, g, b, _] = seed.to_be_bytes();
+            image::Rgb([r, g, b])
+        });
+
+        let mut png = Cursor::new(Vec::new());
+        image.write_to(&mut png, ImageFormat::Png).unwrap();
+        png.into_inner()
+    }
+
+    #[test]
+    fn images_are_scaled_down_to_fit() {
+        let png = large_png();
+
+        for max_len in [None, Some(64 * 1024), Some(8 * 1024)] {
+            let texture = fit(&png, max_len).unwrap();
+            assert!(max_len.is_none_or(|max_len| texture.len() <= max_len as usize));
+
+            assert_eq!(image::guess_format(&texture).unwrap(), ImageFormat::Jpeg);
+            let image = image::load_from_memory(&texture).unwrap();
+            assert!(image.width().max(image.height()) <= 256);
+        }
+
+        assert!(fit(&png, Some(100)).is_err());
+    }
+
+    #[tokio::test]
+    async fn no_image_is_an_empty_texture() {
+        assert!(load(None, None, Some(1024)).await.unwrap().is_empty());
+        assert!(load(None, None, None).await.unwrap().is_empty());
+    }
+}
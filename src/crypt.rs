@@ -0,0 +1,486 @@
+use std::time::{Duration, Instant};
+
+use aes::cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::Aes128;
+
+const AES_BLOCK_SIZE: usize = 16;
+
+/// Minimum time between two nonce resync requests, and the time without a
+/// successfully decrypted packet after which we consider ourselves out of sync.
+const RESYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+/**
+ * Multiply a block by two in GF(2^128), as done by the S2 macro in Mumble's
+ * CryptStateOCB2.cpp.
+ */
+fn s2(block: u128) -> u128 {
+    let carry = block >> 127;
+    (block << 1) ^ (carry * 0x87)
+}
+
+/**
+ * Multiply a block by three in GF(2^128).
+ */
+fn s3(block: u128) -> u128 {
+    block ^ s2(block)
+}
+
+fn increment_iv(iv: &mut [u8; AES_BLOCK_SIZE], start: usize) {
+    for byte in iv[start..].iter_mut() {
+        *byte = byte.wrapping_add(1);
+        if *byte != 0 {
+            break;
+        }
+    }
+}
+
+fn decrement_iv(iv: &mut [u8; AES_BLOCK_SIZE], start: usize) {
+    for byte in iv[start..].iter_mut() {
+        let old = *byte;
+        *byte = byte.wrapping_sub(1);
+        if old != 0 {
+            break;
+        }
+    }
+}
+
+/**
+ * OCB2-AES128 state for the Mumble UDP voice channel.
+ * Translated from CryptStateOCB2.cpp in the Mumble source tree.
+ */
+pub struct CryptState {
+    cipher: Option<Aes128>,
+    encrypt_iv: [u8; AES_BLOCK_SIZE],
+    decrypt_iv: [u8; AES_BLOCK_SIZE],
+    decrypt_history: [u8; 256],
+
+    pub good: u32,
+    pub late: u32,
+    pub lost: u32,
+    pub resync: u32,
+
+    last_good: Instant,
+    last_request: Instant,
+}
+
+impl CryptState {
+    pub fn new() -> Self {
+        CryptState {
+            cipher: None,
+            encrypt_iv: [0; AES_BLOCK_SIZE],
+            decrypt_iv: [0; AES_BLOCK_SIZE],
+            decrypt_history: [0; 256],
+            good: 0,
+            late: 0,
+            lost: 0,
+            resync: 0,
+            last_good: Instant::now(),
+            last_request: Instant::now(),
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    pub fn set_key(
+        &mut self,
+        key: &[u8],
+        client_nonce: &[u8],
+        server_nonce: &[u8],
+    ) -> anyhow::Result<()> {
+        if key.len() != AES_BLOCK_SIZE
+            || client_nonce.len() != AES_BLOCK_SIZE
+            || server_nonce.len() != AES_BLOCK_SIZE
+        {
+            anyhow::bail!("invalid CryptSetup key or nonce length");
+        }
+
+        *self = CryptState::new();
+        self.cipher = Some(Aes128::new(GenericArray::from_slice(key)));
+        self.encrypt_iv.copy_from_slice(client_nonce);
+        self.decrypt_iv.copy_from_slice(server_nonce);
+
+        Ok(())
+    }
+
+    pub fn set_decrypt_iv(&mut self, server_nonce: &[u8]) -> anyhow::Result<()> {
+        if server_nonce.len() != AES_BLOCK_SIZE {
+            anyhow::bail!("invalid server nonce length");
+        }
+
+        self.decrypt_iv.copy_from_slice(server_nonce);
+        self.resync += 1;
+
+        Ok(())
+    }
+
+    pub fn encrypt_iv(&self) -> &[u8] {
+        &self.encrypt_iv
+    }
+
+    /**
+     * Whether we have gone long enough without a good packet that we should
+     * ask the server for a new nonce. Rate-limits itself to one request per
+     * RESYNC_INTERVAL.
+     */
+    pub fn should_request_resync(&mut self) -> bool {
+        if self.last_good.elapsed() > RESYNC_INTERVAL
+            && self.last_request.elapsed() > RESYNC_INTERVAL
+        {
+            self.last_request = Instant::now();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn aes_encrypt(cipher: &Aes128, block: u128) -> u128 {
+        let mut buf = GenericArray::from(block.to_be_bytes());
+        cipher.encrypt_block(&mut buf);
+        u128::from_be_bytes(buf.into())
+    }
+
+    fn aes_decrypt(cipher: &Aes128, block: u128) -> u128 {
+        let mut buf = GenericArray::from(block.to_be_bytes());
+        cipher.decrypt_block(&mut buf);
+        u128::from_be_bytes(buf.into())
+    }
+
+    fn read_block(data: &[u8]) -> u128 {
+        u128::from_be_bytes(data[..AES_BLOCK_SIZE].try_into().unwrap())
+    }
+
+    /**
+     * Encrypt a packet, prefixing it with the low byte of the IV and the
+     * first three bytes of the tag as Mumble expects.
+     */
+    pub fn encrypt(&mut self, plain: &[u8]) -> Vec<u8> {
+        increment_iv(&mut self.encrypt_iv, 0);
+
+        let cipher = self
+            .cipher
+            .as_ref()
+            .expect("encrypt called before key was set");
+        let (encrypted, tag) = Self::ocb_encrypt(cipher, plain, &self.encrypt_iv);
+
+        let mut out = Vec::with_capacity(encrypted.len() + 4);
+        out.push(self.encrypt_iv[0]);
+        out.extend_from_slice(&tag.to_be_bytes()[..3]);
+        out.extend(encrypted);
+
+        out
+    }
+
+    /**
+     * Decrypt a packet, updating the decrypt IV and the good/late/lost
+     * statistics. Returns None if the packet was a replay or failed to verify.
+     */
+    pub fn decrypt(&mut self, source: &[u8]) -> Option<Vec<u8>> {
+        let cipher = self.cipher.as_ref()?;
+
+        if source.len() < 4 {
+            return None;
+        }
+
+        let save_iv = self.decrypt_iv;
+        let iv_byte = source[0];
+        let mut restore = false;
+
+        let mut lost: i32 = 0;
+        let mut late: u32 = 0;
+
+        if self.decrypt_iv[0].wrapping_add(1) == iv_byte {
+            // In order as expected.
+            if iv_byte > self.decrypt_iv[0] {
+                self.decrypt_iv[0] = iv_byte;
+            } else if iv_byte < self.decrypt_iv[0] {
+                self.decrypt_iv[0] = iv_byte;
+                increment_iv(&mut self.decrypt_iv, 1);
+            } else {
+                return None;
+            }
+        } else {
+            // This is either out of order or a repeat.
+            let mut diff = iv_byte as i32 - self.decrypt_iv[0] as i32;
+            if diff > 128 {
+                diff -= 256;
+            } else if diff < -128 {
+                diff += 256;
+            }
+
+            if iv_byte < self.decrypt_iv[0] && diff > -30 && diff < 0 {
+                // Late packet, but no wraparound.
+                late = 1;
+                lost = -1;
+                self.decrypt_iv[0] = iv_byte;
+                restore = true;
+            } else if iv_byte > self.decrypt_iv[0] && diff > -30 && diff < 0 {
+                // Last was 0x02, here comes 0xff from last round.
+                late = 1;
+                lost = -1;
+                self.decrypt_iv[0] = iv_byte;
+                decrement_iv(&mut self.decrypt_iv, 1);
+                restore = true;
+            } else if iv_byte > self.decrypt_iv[0] && diff > 0 {
+                // Lost a few packets, but beyond that we're good.
+                lost = iv_byte as i32 - self.decrypt_iv[0] as i32 - 1;
+                self.decrypt_iv[0] = iv_byte;
+            } else if iv_byte < self.decrypt_iv[0] && diff > 0 {
+                // Lost a few packets, and wrapped around.
+                lost = 256 - self.decrypt_iv[0] as i32 + iv_byte as i32 - 1;
+                self.decrypt_iv[0] = iv_byte;
+                increment_iv(&mut self.decrypt_iv, 1);
+            } else {
+                return None;
+            }
+
+            if self.decrypt_history[self.decrypt_iv[0] as usize] == self.decrypt_iv[1] {
+                self.decrypt_iv = save_iv;
+                return None;
+            }
+        }
+
+        let plain = Self::ocb_decrypt(cipher, &source[4..], &self.decrypt_iv)
+            .filter(|(_, tag)| tag.to_be_bytes()[..3] == source[1..4]);
+
+        let Some((plain, _)) = plain else {
+            self.decrypt_iv = save_iv;
+            return None;
+        };
+
+        self.decrypt_history[self.decrypt_iv[0] as usize] = self.decrypt_iv[1];
+
+        if restore {
+            self.decrypt_iv = save_iv;
+        }
+
+        self.good += 1;
+        self.late += late;
+        self.lost = self.lost.saturating_add_signed(lost);
+        self.last_good = Instant::now();
+
+        Some(plain)
+    }
+
+    fn ocb_encrypt(cipher: &Aes128, plain: &[u8], nonce: &[u8; AES_BLOCK_SIZE]) -> (Vec<u8>, u128) {
+        let mut encrypted = Vec::with_capacity(plain.len());
+
+        let mut delta = Self::aes_encrypt(cipher, u128::from_be_bytes(*nonce));
+        let mut checksum: u128 = 0;
+
+        let mut rest = plain;
+        while rest.len() > AES_BLOCK_SIZE {
+            let block = Self::read_block(rest);
+
+            // Counter-cryptanalysis described in section 9 of https://eprint.iacr.org/2019/311
+            // For an attack, the second to last block must be all 0 except for the last byte.
+            // Digital silence produces such blocks, so we flip a bit that should not be audible.
+            let flip_a_bit = rest.len() - AES_BLOCK_SIZE <= AES_BLOCK_SIZE
+                && rest[..AES_BLOCK_SIZE - 1].iter().all(|&b| b == 0);
+            let flip: u128 = if flip_a_bit { 1 << 120 } else { 0 };
+
+            delta = s2(delta);
+            let tmp = Self::aes_encrypt(cipher, (delta ^ block) ^ flip);
+            encrypted.extend_from_slice(&(delta ^ tmp).to_be_bytes());
+            checksum ^= block ^ flip;
+
+            rest = &rest[AES_BLOCK_SIZE..];
+        }
+
+        delta = s2(delta);
+        let pad = Self::aes_encrypt(cipher, (rest.len() as u128 * 8) ^ delta);
+
+        let mut tmp = pad.to_be_bytes();
+        tmp[..rest.len()].copy_from_slice(rest);
+        let tmp = u128::from_be_bytes(tmp);
+
+        checksum ^= tmp;
+        encrypted.extend_from_slice(&(pad ^ tmp).to_be_bytes()[..rest.len()]);
+
+        delta = s3(delta);
+        let tag = Self::aes_encrypt(cipher, delta ^ checksum);
+
+        (encrypted, tag)
+    }
+
+    fn ocb_decrypt(
+        cipher: &Aes128,
+        encrypted: &[u8],
+        nonce: &[u8; AES_BLOCK_SIZE],
+    ) -> Option<(Vec<u8>, u128)> {
+        let mut plain = Vec::with_capacity(encrypted.len());
+
+        let mut delta = Self::aes_encrypt(cipher, u128::from_be_bytes(*nonce));
+        let mut checksum: u128 = 0;
+
+        let mut rest = encrypted;
+        while rest.len() > AES_BLOCK_SIZE {
+            let block = Self::read_block(rest);
+
+            delta = s2(delta);
+            let tmp = delta ^ Self::aes_decrypt(cipher, delta ^ block);
+            plain.extend_from_slice(&tmp.to_be_bytes());
+            checksum ^= tmp;
+
+            rest = &rest[AES_BLOCK_SIZE..];
+        }
+
+        delta = s2(delta);
+        let pad = Self::aes_encrypt(cipher, (rest.len() as u128 * 8) ^ delta);
+
+        let mut tmp = [0u8; AES_BLOCK_SIZE];
+        tmp[..rest.len()].copy_from_slice(rest);
+        let tmp = u128::from_be_bytes(tmp) ^ pad;
+
+        checksum ^= tmp;
+        plain.extend_from_slice(&tmp.to_be_bytes()[..rest.len()]);
+
+        // Counter-cryptanalysis described in section 9 of https://eprint.iacr.org/2019/311
+        // In an attack, the decrypted last block would need to equal `delta ^ len(128)`.
+        if tmp.to_be_bytes()[..AES_BLOCK_SIZE - 1] == delta.to_be_bytes()[..AES_BLOCK_SIZE - 1] {
+            return None;
+        }
+
+        delta = s3(delta);
+        let tag = Self::aes_encrypt(cipher, delta ^ checksum);
+
+        Some((plain, tag))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; AES_BLOCK_SIZE] = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E,
+        0x0F,
+    ];
+    const CLIENT_NONCE: [u8; AES_BLOCK_SIZE] = [0x11; AES_BLOCK_SIZE];
+    const SERVER_NONCE: [u8; AES_BLOCK_SIZE] = [0x22; AES_BLOCK_SIZE];
+
+    /**
+     * Our state and the server's, set up from the same CryptSetup.
+     */
+    fn pair() -> (CryptState, CryptState) {
+        let mut client = CryptState::new();
+        client.set_key(&KEY, &CLIENT_NONCE, &SERVER_NONCE).unwrap();
+
+        let mut server = CryptState::new();
+        server.set_key(&KEY, &SERVER_NONCE, &CLIENT_NONCE).unwrap();
+
+        (client, server)
+    }
+
+    #[test]
+    fn known_answer_vectors() {
+        // From Mumble's TestCrypt.cpp, which takes them from draft-krovetz-ocb-00.
+        let cipher = Aes128::new(GenericArray::from_slice(&KEY));
+
+        let (encrypted, tag) = CryptState::ocb_encrypt(&cipher, &[], &KEY);
+        assert!(encrypted.is_empty());
+        assert_eq!(tag, 0xBF31_0813_0773_AD5E_C70E_C69E_7875_A7B0);
+
+        let plain: Vec<u8> = (0..40).collect();
+        let (encrypted, tag) = CryptState::ocb_encrypt(&cipher, &plain, &KEY);
+        assert_eq!(
+            encrypted,
+            [
+                0xF7, 0x5D, 0x6B, 0xC8, 0xB4, 0xDC, 0x8D, 0x66, 0xB8, 0x36, 0xA2, 0xB0, 0x8B, 0x32,
+                0xA6, 0x36, 0x9F, 0x1C, 0xD3, 0xC5, 0x22, 0x8D, 0x79, 0xFD, 0x6C, 0x26, 0x7F, 0x5F,
+                0x6A, 0xA7, 0xB2, 0x31, 0xC7, 0xDF, 0xB9, 0xD5, 0x99, 0x51, 0xAE, 0x9C,
+            ]
+        );
+        assert_eq!(tag, 0x9DB0_CDF8_80F7_3E3E_10D4_EB32_1776_6688);
+
+        let (decrypted, decrypted_tag) =
+            CryptState::ocb_decrypt(&cipher, &encrypted, &KEY).unwrap();
+        assert_eq!(decrypted, plain);
+        assert_eq!(decrypted_tag, tag);
+    }
+
+    #[test]
+    fn packets_survive_a_round_trip() {
+        let (mut client, mut server) = pair();
+
+        // Enough packets for the low IV byte to wrap around a few times.
+        for i in 0..1000usize {
+            // No long runs of zeros, which encryption alters on purpose.
+            let plain: Vec<u8> = (0..i % 70).map(|b| (b + i + 1) as u8).collect();
+
+            let encrypted = client.encrypt(&plain);
+            assert_eq!(encrypted.len(), plain.len() + 4);
+            assert_eq!(server.decrypt(&encrypted).unwrap(), plain);
+
+            let encrypted = server.encrypt(&plain);
+            assert_eq!(client.decrypt(&encrypted).unwrap(), plain);
+        }
+
+        assert_eq!(server.good, 1000);
+        assert_eq!((server.late, server.lost), (0, 0));
+    }
+
+    #[test]
+    fn tampered_packets_are_rejected() {
+        let (mut client, mut server) = pair();
+        let encrypted = client.encrypt(b"some voice data, longer than a block");
+
+        for i in 1..encrypted.len() {
+            let mut tampered = encrypted.clone();
+            tampered[i] ^= 0x01;
+            assert!(server.decrypt(&tampered).is_none(), "byte {}", i);
+        }
+
+        // Failed packets leave the state as it was.
+        assert!(server.decrypt(&encrypted).is_some());
+        assert_eq!(server.good, 1);
+    }
+
+    #[test]
+    fn replayed_late_and_lost_packets_are_counted() {
+        let (mut client, mut server) = pair();
+        let packets: Vec<Vec<u8>> = (1..7u8).map(|i| client.encrypt(&[i; 20])).collect();
+
+        assert_eq!(server.decrypt(&packets[0]).unwrap(), [1; 20]);
+        assert!(server.decrypt(&packets[0]).is_none());
+
+        // Two lost...
+        assert_eq!(server.decrypt(&packets[3]).unwrap(), [4; 20]);
+        assert_eq!((server.good, server.late, server.lost), (2, 0, 2));
+
+        // ...until one of them turns up late, which is no replay.
+        assert_eq!(server.decrypt(&packets[1]).unwrap(), [2; 20]);
+        assert_eq!((server.good, server.late, server.lost), (3, 1, 1));
+        assert!(server.decrypt(&packets[1]).is_none());
+
+        // Back in order after the late one.
+        assert_eq!(server.decrypt(&packets[4]).unwrap(), [5; 20]);
+        assert_eq!(server.decrypt(&packets[5]).unwrap(), [6; 20]);
+        assert_eq!((server.good, server.late, server.lost), (5, 1, 1));
+    }
+
+    #[test]
+    fn resync_recovers_from_nonce_drift() {
+        let (mut client, mut server) = pair();
+
+        // The server misses so many packets its nonce can't catch up.
+        for _ in 0..300 {
+            client.encrypt(b"lost");
+        }
+        let encrypted = client.encrypt(b"after the gap");
+        assert!(server.decrypt(&encrypted).is_none());
+
+        // It gets the current nonce, as the CryptSetup exchange would do.
+        let mut nonce = client.encrypt_iv().to_vec();
+        decrement_iv((&mut nonce[..]).try_into().unwrap(), 0);
+        server.set_decrypt_iv(&nonce).unwrap();
+
+        assert_eq!(server.decrypt(&encrypted).unwrap(), b"after the gap");
+        assert_eq!(
+            server.decrypt(&client.encrypt(b"and on")).unwrap(),
+            b"and on"
+        );
+        assert_eq!(server.resync, 1);
+    }
+}
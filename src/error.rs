@@ -0,0 +1,176 @@
+use std::fmt;
+
+use crate::{
+    mumble_proto::{self, permission_denied::DenyType, reject::RejectType},
+    state::ServerState,
+};
+
+/**
+ * The server refused our connection attempt with a Reject message.
+ */
+#[derive(Debug, Clone)]
+pub struct Rejected {
+    pub kind: RejectType,
+    pub reason: Option<String>,
+}
+
+impl Rejected {
+    pub fn new(msg: &mumble_proto::Reject) -> Self {
+        Rejected {
+            kind: msg.r#type(),
+            reason: msg.reason.clone(),
+        }
+    }
+
+    /**
+     * Whether reconnecting is pointless until the configuration is fixed.
+     */
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self.kind,
+            RejectType::WrongVersion
+                | RejectType::InvalidUsername
+                | RejectType::WrongUserPw
+                | RejectType::WrongServerPw
+                | RejectType::NoCertificate
+                | RejectType::AuthenticatorFail
+        )
+    }
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self.kind {
+            RejectType::None => "connection rejected",
+            RejectType::WrongVersion => "our client version is not supported by the server",
+            RejectType::InvalidUsername => "the configured username is not valid on this server",
+            RejectType::WrongUserPw => {
+                "wrong password for the registered user (is the certificate the one it was registered with?)"
+            }
+            RejectType::WrongServerPw => "wrong or missing server password",
+            RejectType::UsernameInUse => "the username is already in use",
+            RejectType::ServerFull => "the server is full",
+            RejectType::NoCertificate => "the server requires a valid client certificate",
+            RejectType::AuthenticatorFail => "the server's authenticator rejected us",
+        };
+
+        write!(f, "Server rejected connection: {}", description)?;
+
+        if let Some(reason) = &self.reason {
+            write!(f, " ({})", reason)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// Names of the ACL permission bits, from ChanACL.h in the Mumble source tree.
+const PERMISSION_NAMES: [(u32, &str); 17] = [
+    (0x1, "Write ACL"),
+    (0x2, "Traverse"),
+    (0x4, "Enter"),
+    (0x8, "Speak"),
+    (0x10, "Mute/Deafen"),
+    (0x20, "Move"),
+    (0x40, "Make channel"),
+    (0x80, "Link channel"),
+    (0x100, "Whisper"),
+    (0x200, "Text message"),
+    (0x400, "Make temporary channel"),
+    (0x800, "Listen"),
+    (0x10000, "Kick"),
+    (0x20000, "Ban"),
+    (0x40000, "Register"),
+    (0x80000, "Self-register"),
+    (0x100000, "Reset user content"),
+];
+
+/**
+ * An action of ours was refused by the server with a PermissionDenied message.
+ * Channel and user names are resolved when the error is created, so it can be
+ * shown to users as-is.
+ */
+#[derive(Debug, Clone)]
+pub struct PermissionDenied {
+    pub kind: DenyType,
+    pub permission: Option<u32>,
+    pub channel: Option<String>,
+    pub user: Option<String>,
+    pub reason: Option<String>,
+    pub name: Option<String>,
+}
+
+impl PermissionDenied {
+    pub fn new(msg: &mumble_proto::PermissionDenied, server_state: &ServerState) -> Self {
+        PermissionDenied {
+            kind: msg.r#type(),
+            permission: msg.permission,
+            channel: msg.channel_id.map(|id| server_state.channel_path(id)),
+            user: msg
+                .session
+                .and_then(|session| server_state.user(session))
+                .map(|user| user.name.clone()),
+            reason: msg.reason.clone(),
+            name: msg.name.clone(),
+        }
+    }
+}
+
+impl fmt::Display for PermissionDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            DenyType::Text => write!(
+                f,
+                "Permission denied: {}",
+                self.reason.as_deref().unwrap_or("no reason given")
+            ),
+            DenyType::Permission => {
+                let permissions: Vec<&str> = PERMISSION_NAMES
+                    .iter()
+                    .filter(|(bit, _)| self.permission.is_some_and(|p| p & bit != 0))
+                    .map(|(_, name)| *name)
+                    .collect();
+
+                write!(f, "Permission denied")?;
+
+                if !permissions.is_empty() {
+                    write!(f, ": missing {}", permissions.join(", "))?;
+                }
+
+                if let Some(channel) = &self.channel {
+                    write!(f, " in channel {}", channel)?;
+                }
+
+                if let Some(user) = &self.user {
+                    write!(f, " for {}", user)?;
+                }
+
+                Ok(())
+            }
+            DenyType::SuperUser => write!(f, "Cannot modify SuperUser"),
+            DenyType::ChannelName => write!(f, "Invalid channel name"),
+            DenyType::TextTooLong => write!(f, "Text message too long"),
+            DenyType::H9k => write!(f, "The flux capacitor was spelled wrong"),
+            DenyType::TemporaryChannel => write!(f, "Not permitted in a temporary channel"),
+            DenyType::MissingCertificate => {
+                write!(f, "This requires the user to have a certificate")
+            }
+            DenyType::UserName => write!(
+                f,
+                "Invalid username {:?}",
+                self.name.as_deref().unwrap_or_default()
+            ),
+            DenyType::ChannelFull => write!(f, "Channel is full"),
+            DenyType::NestingLimit => write!(f, "Channels are nested too deeply"),
+            DenyType::ChannelCountLimit => write!(f, "Maximum channel count reached"),
+            DenyType::ChannelListenerLimit => {
+                write!(f, "Maximum number of listeners in this channel reached")
+            }
+            DenyType::UserListenerLimit => write!(f, "Maximum number of listeners reached"),
+        }
+    }
+}
+
+impl std::error::Error for PermissionDenied {}
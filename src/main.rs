@@ -1,23 +1,51 @@
+mod avatar;
+mod crypt;
+mod error;
+#[cfg(test)]
+mod mock_server;
 mod net;
+mod now_playing;
+mod recorder;
+mod relay;
 mod sound;
 mod spotify;
+mod state;
+mod text;
+mod tls;
 mod types;
+mod udp;
+mod voice;
 mod youtube;
 
 use librespot::core::SpotifyUri;
-use log::{debug, info};
-use std::collections::VecDeque;
-use tokio::sync::mpsc;
+use log::{debug, info, warn};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{broadcast, mpsc, watch},
+    time::sleep_until,
+};
 use tokio_rustls::rustls;
 use tokio_util::sync::CancellationToken;
-use types::{Config, MumbleMsg, PlayerAction};
+use types::{Config, MumbleMsg, PlayerAction, Request, TextTarget, VoiceRecipient};
 
+use crate::state::{ServerLimits, ServerState};
 use crate::types::{Song, SongType};
 
 pub mod mumble_proto {
     include!(concat!(env!("OUT_DIR"), "/mumble_proto.rs"));
 }
 
+pub mod mumble_udp {
+    include!(concat!(env!("OUT_DIR"), "/mumble_udp.rs"));
+}
+
 fn load_config(filename: &str) -> anyhow::Result<Config> {
     let file = std::fs::File::open(filename)?;
     let reader = std::io::BufReader::new(file);
@@ -33,63 +61,112 @@ enum PlayerState {
     Playing,
     Paused,
     Stopped,
+    Suspended,
+}
+
+/**
+ * Start decoding `song`, returning where its samples arrive.
+ */
+fn decode(song: &Song, cancel_tok: CancellationToken) -> mpsc::Receiver<Vec<i16>> {
+    let (sink, source) = mpsc::channel(32);
+
+    match song.song_type {
+        SongType::Spotify => {
+            tokio::spawn(spotify::play_song(
+                SpotifyUri::from_uri(&song.id).unwrap(),
+                sink,
+                cancel_tok,
+            ));
+        }
+        SongType::YouTube => {
+            tokio::spawn(youtube::stream_url(song.id.clone(), sink, cancel_tok));
+        }
+    }
+
+    source
 }
 
 async fn player_task(
     mut queue_recv: mpsc::Receiver<PlayerAction>,
     msg_sender: mpsc::Sender<MumbleMsg>,
+    default_avatar: Option<String>,
+    streamer: sound::AudioSender,
+    mut events: mpsc::Receiver<sound::SongEvent>,
 ) -> anyhow::Result<()> {
-    let mut queue: VecDeque<types::Song> = VecDeque::new();
+    let mut queue: VecDeque<(types::Song, Request)> = VecDeque::new();
+    let mut current: Option<(types::Song, Request)> = None;
 
     let mut state = PlayerState::Ready;
 
-    let (finish_send, mut finish_recv) = mpsc::channel::<()>(1);
+    let mut limits = ServerLimits::default();
+    let mut comment = now_playing::CommentUpdater::default();
+    let mut avatar = avatar::Avatar::new(default_avatar);
 
     let mut cancel_tok = CancellationToken::new();
 
-    let streamer = sound::AudioSender::new(msg_sender.clone(), finish_send.clone());
+    // Whether the current song was decoded in full, so the next can be decoded ahead.
+    let mut source_ended = false;
+    // The front of the queue, once it is being decoded ahead.
+    let mut preloaded: Option<CancellationToken> = None;
+    // The number the streamer gave the current song, so events about earlier songs can be ignored.
+    let mut generation = 0;
 
     loop {
+        let next_comment_update = comment.next_update(state == PlayerState::Playing);
+
         tokio::select! {
             action = queue_recv.recv() => {
                 let action = action.unwrap();
                 match action {
-                    PlayerAction::PlaySong(song) => {
+                    PlayerAction::PlaySong(song, request) => {
                         if state == PlayerState::Playing
                         {
                             net::send_text_message(
                                 &msg_sender,
+                                request.reply_to,
                                 format!("Enqueueing song: {}", song.name)
                             ).await?;
                         }
-                        queue.push_back(song);
+                        queue.push_back((song, request));
+                        comment.changed();
 
                         if state == PlayerState::Stopped {
                             state = PlayerState::Ready;
                         }
                     },
                     PlayerAction::Next => {
-                        if state == PlayerState::Playing || state == PlayerState::Paused {
+                        if matches!(state, PlayerState::Playing | PlayerState::Paused | PlayerState::Suspended) {
                             cancel_tok.cancel();
                             cancel_tok = CancellationToken::new();
                             streamer.stop().await?;
                         }
+                        if let Some(preload) = preloaded.take() {
+                            preload.cancel();
+                        }
                         state = PlayerState::Ready;
+                        current = None;
+                        comment.changed();
                     },
                     PlayerAction::Stop => {
-                        if state == PlayerState::Playing || state == PlayerState::Paused {
+                        if matches!(state, PlayerState::Playing | PlayerState::Paused | PlayerState::Suspended) {
                             cancel_tok.cancel();
                             cancel_tok = CancellationToken::new();
                             streamer.stop().await?;
                         }
+                        if let Some(preload) = preloaded.take() {
+                            preload.cancel();
+                        }
 
                         state = PlayerState::Stopped;
+                        current = None;
+                        comment.changed();
                     },
                     PlayerAction::Pause => {
                         if state == PlayerState::Playing {
                             debug!("Pausing streamer.");
                             streamer.stop().await?;
                             state = PlayerState::Paused;
+                            comment.changed();
                         }
                     },
                     PlayerAction::Resume => {
@@ -97,59 +174,164 @@ async fn player_task(
                             debug!("Resuming paused streamer.");
                             streamer.resume().await;
                             state = PlayerState::Playing;
+                            comment.changed();
                         }
                     },
-                    PlayerAction::ShowQueue => {
-                        let mut output = String::from("Songs in queue: ");
-                        for (i, song) in queue.iter().enumerate()
-                        {
-                            output.push_str(&song.name);
-                            if i != queue.len() - 1
+                    PlayerAction::ShowQueue(reply_to, page) => {
+                        let output = if queue.is_empty() {
+                            String::from("The queue is empty.")
+                        } else {
+                            let pages = queue.len().div_ceil(QUEUE_PAGE_SIZE);
+                            let page = page.clamp(1, pages);
+
+                            let mut output = format!("Songs in queue (page {} of {}):", page, pages);
+                            for (i, (song, _)) in queue.iter().enumerate().skip((page - 1) * QUEUE_PAGE_SIZE).take(QUEUE_PAGE_SIZE)
                             {
-                                output.push_str(", ");
+                                output.push_str(&format!("<br>{}. {}", i + 1, text::escape_html(&song.name)));
                             }
-                        }
 
-                        net::send_text_message(&msg_sender, &output).await?;
+                            output
+                        };
+
+                        net::send_text_message(&msg_sender, reply_to, &output).await?;
                     },
+                    PlayerAction::ShowBuffer(reply_to) => {
+                        let stats = streamer.buffer_stats().await;
+                        net::send_text_message(
+                            &msg_sender,
+                            reply_to,
+                            format!(
+                                "Buffered {:.1} s of at most {:.0} s{}, {} underruns so far.",
+                                stats.buffered.as_secs_f64(),
+                                stats.high_water.as_secs_f64(),
+                                if stats.filling { " (filling)" } else { "" },
+                                stats.underruns
+                            )
+                        ).await?;
+                    }
                     PlayerAction::SetVolume(vol) => {
                         streamer.set_volume(vol).await;
                     }
+                    PlayerAction::SetLimits(new_limits) => {
+                        streamer.set_max_bandwidth(new_limits.max_bandwidth).await;
+                        limits = new_limits;
+                        comment.changed();
+                    }
+                    PlayerAction::Suspend => {
+                        if state == PlayerState::Playing {
+                            debug!("Suspending streamer until reconnected.");
+                            streamer.stop().await?;
+                            state = PlayerState::Suspended;
+                        }
+                    }
+                    PlayerAction::Restore => {
+                        if state == PlayerState::Suspended {
+                            debug!("Resuming suspended streamer.");
+                            streamer.resume().await;
+                            state = PlayerState::Playing;
+                        }
+                        comment.invalidate();
+                        avatar.invalidate();
+                    }
                 }
             },
-            _ = finish_recv.recv() => {
-                state = PlayerState::Ready;
-            }
-        }
+            event = events.recv() => {
+                let event = event.unwrap();
+                if event.generation != generation {
+                    debug!("Ignoring {:?} about an earlier song.", event);
+                    continue;
+                }
 
-        if state == PlayerState::Ready && !queue.is_empty() {
-            debug!("Starting new song playback...");
-            let song = queue.pop_front().unwrap();
+                match event.event {
+                    sound::StreamEvent::SourceEnded => {
+                        source_ended = true;
+                    }
+                    sound::StreamEvent::Advanced => {
+                        generation += 1;
 
-            net::send_text_message(&msg_sender, format!("Playing song: {}", song.name)).await?;
+                        if let Some(preload) = preloaded.take() {
+                            let (song, request) = queue.pop_front().unwrap();
 
-            let (sink, source) = mpsc::channel(32);
+                            net::send_text_message(
+                                &msg_sender,
+                                request.reply_to,
+                                format!("Playing song: {}", song.name),
+                            ).await?;
 
-            match song.song_type {
-                SongType::Spotify => {
-                    tokio::spawn(spotify::play_song(
-                        SpotifyUri::from_uri(&song.id).unwrap(),
-                        sink,
-                        cancel_tok.clone(),
-                    ));
-                }
-                SongType::YouTube => {
-                    tokio::spawn(youtube::stream_url(song.id, sink, cancel_tok.clone()));
+                            cancel_tok = preload;
+                            source_ended = false;
+                            current = Some((song, request));
+                            comment.changed();
+                        }
+                    }
+                    sound::StreamEvent::Finished => {
+                        // A song handed over too late to be joined is started afresh.
+                        if let Some(preload) = preloaded.take() {
+                            preload.cancel();
+                        }
+                        state = PlayerState::Ready;
+                        current = None;
+                        comment.changed();
+                    }
                 }
             }
+            _ = sleep_until(next_comment_update.unwrap_or_else(Instant::now).into()), if next_comment_update.is_some() => {
+                let position = streamer.position().await;
+                let now_playing = current.as_ref().map(|(song, request)| now_playing::Current {
+                    song,
+                    request,
+                    position,
+                    paused: state != PlayerState::Playing,
+                });
 
-            streamer.start(source).await?;
+                let panel = now_playing::render(now_playing.as_ref(), &queue, &limits);
+                comment.update(&msg_sender, panel).await?;
+            }
+        }
+
+        if state == PlayerState::Ready && !queue.is_empty() {
+            debug!("Starting new song playback...");
+            let (song, request) = queue.pop_front().unwrap();
+
+            net::send_text_message(
+                &msg_sender,
+                request.reply_to,
+                format!("Playing song: {}", song.name),
+            )
+            .await?;
+
+            generation = streamer.start(decode(&song, cancel_tok.clone())).await?;
 
             state = PlayerState::Playing;
+            source_ended = false;
+            current = Some((song, request));
+            comment.changed();
         }
+
+        if matches!(
+            state,
+            PlayerState::Playing | PlayerState::Paused | PlayerState::Suspended
+        ) && source_ended
+            && preloaded.is_none()
+        {
+            if let Some((song, _)) = queue.front() {
+                debug!("Decoding the next song ahead...");
+                let preload = CancellationToken::new();
+                streamer.queue_next(decode(song, preload.clone())).await;
+                preloaded = Some(preload);
+            }
+        }
+
+        let image_url = current
+            .as_ref()
+            .and_then(|(song, _)| song.image_url.as_deref());
+        avatar.show(image_url, &msg_sender, &limits);
     }
 }
 
+/// Songs per page of .show output.
+const QUEUE_PAGE_SIZE: usize = 20;
+
 const SPOTIFY_TRACK_URL_BASE: &str = "https://open.spotify.com/track/";
 const SPOTIFY_PLAYLIST_URL_BASE: &str = "https://open.spotify.com/playlist/";
 
@@ -178,11 +360,34 @@ fn tag_stripper(input: &str) -> String {
 async fn handle_message(
     msg: &MumbleMsg,
     queue_sink: &mpsc::Sender<PlayerAction>,
-    cfg: &Config,
-) -> anyhow::Result<()> {
+    msg_sender: &mpsc::Sender<MumbleMsg>,
+    cfg: &mut Config,
+    server_state: &ServerState,
+    voice_target: &mut VoiceTarget,
+    recording: &mut Option<recorder::Recording>,
+) -> anyhow::Result<Option<CommandRequest>> {
+    // What a command asked of the server, for reporting permission errors.
+    let mut sent_request = None;
+
     if let MumbleMsg::TextMessage(msg) = msg {
         if msg.message.starts_with(".") {
             let (cmd, arg) = msg.message.split_once(' ').unwrap_or((&msg.message, ""));
+            let reply_to = TextTarget::reply_to(msg);
+
+            let sender = msg.actor.and_then(|actor| server_state.user(actor));
+            let request = Request {
+                reply_to,
+                requester: sender.map(|sender| sender.name.clone()),
+            };
+            if let Some(sender) = sender {
+                debug!(
+                    "Command {:?} from {:?} in {:?}",
+                    cmd,
+                    sender.name,
+                    server_state.channel_path(sender.channel_id)
+                );
+            }
+
             match cmd {
                 ".stop" => {
                     queue_sink.send(PlayerAction::Stop).await?;
@@ -190,9 +395,7 @@ async fn handle_message(
                 ".sp" => {
                     let arg = tag_stripper(arg);
                     if !arg.is_empty() {
-                        let song = if arg.starts_with(SPOTIFY_TRACK_URL_BASE) {
-                            let rest = &arg[SPOTIFY_TRACK_URL_BASE.len()..];
-
+                        let song = if let Some(rest) = arg.strip_prefix(SPOTIFY_TRACK_URL_BASE) {
                             let track_id = if let Some(idx) = rest.find('?') {
                                 &rest[..idx]
                             } else {
@@ -210,17 +413,19 @@ async fn handle_message(
                         };
 
                         if let Some(song) = song {
-                            queue_sink.send(PlayerAction::PlaySong(song)).await?;
+                            queue_sink
+                                .send(PlayerAction::PlaySong(song, request.clone()))
+                                .await?;
                         }
                     }
                 }
                 ".spplaylist" => {
                     let arg = tag_stripper(arg);
-                    if arg.starts_with(SPOTIFY_PLAYLIST_URL_BASE) {
-                        let playlist_id = if let Some(idx) = arg.find('?') {
-                            &arg[SPOTIFY_PLAYLIST_URL_BASE.len()..idx]
+                    if let Some(rest) = arg.strip_prefix(SPOTIFY_PLAYLIST_URL_BASE) {
+                        let playlist_id = if let Some(idx) = rest.find('?') {
+                            &rest[..idx]
                         } else {
-                            &arg[SPOTIFY_PLAYLIST_URL_BASE.len()..]
+                            rest
                         };
 
                         let uri = format!("spotify:playlist:{}", playlist_id);
@@ -229,12 +434,20 @@ async fn handle_message(
                         let songs = spotify::get_playlist_tracks_by_id(cfg, &uri).await?;
 
                         for song in songs {
-                            queue_sink.send(PlayerAction::PlaySong(song)).await?;
+                            queue_sink
+                                .send(PlayerAction::PlaySong(song, request.clone()))
+                                .await?;
                         }
                     }
                 }
                 ".show" => {
-                    queue_sink.send(PlayerAction::ShowQueue).await?;
+                    let page = arg.trim().parse().unwrap_or(1);
+                    queue_sink
+                        .send(PlayerAction::ShowQueue(reply_to, page))
+                        .await?;
+                }
+                ".buffer" => {
+                    queue_sink.send(PlayerAction::ShowBuffer(reply_to)).await?;
                 }
                 ".next" => {
                     queue_sink.send(PlayerAction::Next).await?;
@@ -248,16 +461,12 @@ async fn handle_message(
                 ".yt" => {
                     let arg = tag_stripper(arg);
 
-                    match youtube::get_title(&arg).await {
+                    match youtube::get_song(arg.clone()).await {
                         Err(e) => debug!("Failed to load title for YouTube URL '{}': {:?}", arg, e),
-                        Ok(name) => {
-                            let song = Song {
-                                name,
-                                id: arg,
-                                song_type: SongType::YouTube,
-                            };
-
-                            queue_sink.send(PlayerAction::PlaySong(song)).await?;
+                        Ok(song) => {
+                            queue_sink
+                                .send(PlayerAction::PlaySong(song, request))
+                                .await?;
                         }
                     }
                 }
@@ -268,18 +477,641 @@ async fn handle_message(
                             .await?;
                     }
                 }
+                ".join" => {
+                    let arg = tag_stripper(arg);
+                    match (server_state.own_session(), server_state.find_channel(&arg)) {
+                        (Some(session), Some(channel_id)) => {
+                            net::join_channel(msg_sender, session, channel_id).await?;
+                            sent_request = CommandRequest::join(msg.actor, session, channel_id);
+                        }
+                        (_, None) => {
+                            net::send_text_message(
+                                msg_sender,
+                                reply_to,
+                                format!("Could not find channel {:?}", arg),
+                            )
+                            .await?;
+                        }
+                        (None, _) => {}
+                    }
+                }
+                ".summon" => {
+                    if let (Some(session), Some(sender)) = (server_state.own_session(), sender) {
+                        net::join_channel(msg_sender, session, sender.channel_id).await?;
+                        sent_request = CommandRequest::join(msg.actor, session, sender.channel_id);
+                    }
+                }
+                ".who" => {
+                    if let Some(sender) = sender {
+                        let users: Vec<String> = server_state
+                            .users_in_channel(sender.channel_id)
+                            .into_iter()
+                            .map(|user| {
+                                let flags = user.flags();
+                                if flags.is_empty() {
+                                    user.name.clone()
+                                } else {
+                                    format!("{} ({})", user.name, flags.join(", "))
+                                }
+                            })
+                            .collect();
+
+                        net::send_text_message(
+                            msg_sender,
+                            reply_to,
+                            format!(
+                                "Users in {}: {}",
+                                server_state.channel_path(sender.channel_id),
+                                users.join(", ")
+                            ),
+                        )
+                        .await?;
+                    }
+                }
+                ".whisper" => {
+                    let arg = tag_stripper(arg);
+                    let names: Vec<&str> = arg
+                        .split('@')
+                        .map(str::trim)
+                        .filter(|name| !name.is_empty())
+                        .collect();
+
+                    let mut recipients = vec![];
+                    let mut found = vec![];
+                    for name in names {
+                        match server_state.find_user(name) {
+                            Some(user) => {
+                                recipients.push(VoiceRecipient::User(user.session));
+                                found.push(user.name.clone());
+                            }
+                            None => {
+                                net::send_text_message(
+                                    msg_sender,
+                                    reply_to,
+                                    format!("Could not find user {:?}", name),
+                                )
+                                .await?;
+                            }
+                        }
+                    }
+
+                    if !recipients.is_empty() {
+                        net::set_voice_target(msg_sender, COMMAND_VOICE_TARGET, &recipients)
+                            .await?;
+                        voice_target
+                            .id
+                            .store(COMMAND_VOICE_TARGET, Ordering::Relaxed);
+
+                        net::send_text_message(
+                            msg_sender,
+                            reply_to,
+                            format!("Whispering to {}", found.join(", ")),
+                        )
+                        .await?;
+
+                        voice_target.command = Some(VoiceCommand::Whisper(found));
+                    }
+                }
+                ".shout" => {
+                    let arg = tag_stripper(arg);
+
+                    match parse_shout_targets(&arg, server_state) {
+                        Ok(recipients) if !recipients.is_empty() => {
+                            net::set_voice_target(msg_sender, COMMAND_VOICE_TARGET, &recipients)
+                                .await?;
+                            voice_target
+                                .id
+                                .store(COMMAND_VOICE_TARGET, Ordering::Relaxed);
+
+                            net::send_text_message(
+                                msg_sender,
+                                reply_to,
+                                format!("Shouting to {}", arg),
+                            )
+                            .await?;
+
+                            voice_target.command = Some(VoiceCommand::Shout(arg));
+                        }
+                        Ok(_) => {
+                            net::send_text_message(
+                                msg_sender,
+                                reply_to,
+                                "Usage: .shout Channel[+children][+links][#group], ...",
+                            )
+                            .await?;
+                        }
+                        Err(e) => {
+                            net::send_text_message(msg_sender, reply_to, e).await?;
+                        }
+                    }
+                }
+                ".talk" => {
+                    voice_target
+                        .id
+                        .store(voice::NORMAL_TALKING, Ordering::Relaxed);
+                    voice_target.command = None;
+                    net::send_text_message(msg_sender, reply_to, "Talking to our channel again.")
+                        .await?;
+                }
+                ".token" => {
+                    let arg = tag_stripper(arg);
+
+                    if !sender.is_some_and(|sender| is_admin(cfg, sender)) {
+                        net::send_text_message(
+                            msg_sender,
+                            reply_to,
+                            "Only admins can change access tokens.",
+                        )
+                        .await?;
+                    } else if let Some(("add", token)) = arg
+                        .split_once(' ')
+                        .map(|(command, token)| (command, token.trim()))
+                        .filter(|(_, token)| !token.is_empty())
+                    {
+                        let tokens = cfg.tokens.get_or_insert_with(Vec::new);
+                        if !tokens.iter().any(|t| t == token) {
+                            tokens.push(token.into());
+                        }
+
+                        net::send_tokens(msg_sender, tokens).await?;
+                        net::send_text_message(msg_sender, reply_to, "Access token added.").await?;
+                    } else {
+                        net::send_text_message(msg_sender, reply_to, "Usage: .token add <token>")
+                            .await?;
+                    }
+                }
+                ".record" => {
+                    let Some(session) = server_state.own_session() else {
+                        return Ok(None);
+                    };
+                    let own_channel = server_state
+                        .user(session)
+                        .map(|user| user.channel_id)
+                        .unwrap_or(0);
+
+                    match (arg.trim(), recording.take()) {
+                        ("start", None) => {
+                            let channel = server_state.channel_path(own_channel);
+                            match recorder::Recording::start(
+                                cfg.recording_dir.as_deref(),
+                                channel.clone(),
+                            ) {
+                                Ok(started) => {
+                                    *recording = Some(started);
+                                    net::set_recording(msg_sender, session, true).await?;
+                                    net::send_text_message(
+                                        msg_sender,
+                                        TextTarget::Channel(own_channel),
+                                        format!("Recording {}.", channel),
+                                    )
+                                    .await?;
+                                }
+                                Err(e) => {
+                                    net::send_text_message(
+                                        msg_sender,
+                                        reply_to,
+                                        format!("Could not start recording: {}", e),
+                                    )
+                                    .await?;
+                                }
+                            }
+                        }
+                        ("start", Some(running)) => {
+                            *recording = Some(running);
+                            net::send_text_message(msg_sender, reply_to, "Already recording.")
+                                .await?;
+                        }
+                        ("stop", Some(running)) => {
+                            net::set_recording(msg_sender, session, false).await?;
+
+                            let reply = match running.stop().await {
+                                Ok(dir) => {
+                                    format!("Recording stopped, saved to {}.", dir.display())
+                                }
+                                Err(e) => format!("Recording failed: {}", e),
+                            };
+                            net::send_text_message(
+                                msg_sender,
+                                TextTarget::Channel(own_channel),
+                                reply,
+                            )
+                            .await?;
+                        }
+                        ("stop", None) => {
+                            net::send_text_message(msg_sender, reply_to, "Not recording.").await?;
+                        }
+                        (_, running) => {
+                            *recording = running;
+                            net::send_text_message(
+                                msg_sender,
+                                reply_to,
+                                "Usage: .record start|stop",
+                            )
+                            .await?;
+                        }
+                    }
+                }
                 _ => {
                     debug!("Unhandled command {:?}", cmd);
                 }
             }
         }
     } else if let MumbleMsg::ServerSync(_) = msg {
-        info!("ServerSync received, connected to server.");
+        info!(
+            "ServerSync received, connected to server as session {:?}.",
+            server_state.own_session()
+        );
+
+        if let Some(session) = server_state.own_session() {
+            restore_presence(msg_sender, cfg, server_state, session).await?;
+
+            if let Some(command) = &voice_target.command {
+                restore_voice_target(msg_sender, server_state, &voice_target.id, command).await?;
+            }
+
+            if cfg.register_self.unwrap_or(false) {
+                register_self(msg_sender, server_state, session).await?;
+            }
+
+            if recording.is_some() {
+                net::set_recording(msg_sender, session, true).await?;
+            }
+        }
+
+        queue_sink
+            .send(PlayerAction::SetLimits(server_state.limits().clone()))
+            .await?;
+        queue_sink.send(PlayerAction::Restore).await?;
+    } else if let MumbleMsg::ServerConfig(_) = msg {
+        let limits = server_state.limits();
+        debug!(
+            "Server limits: message length {:?}, image message length {:?}, HTML {}, bandwidth {:?}, users {:?}",
+            limits.message_length,
+            limits.image_message_length,
+            limits.allow_html,
+            limits.max_bandwidth,
+            limits.max_users
+        );
+
+        queue_sink
+            .send(PlayerAction::SetLimits(limits.clone()))
+            .await?;
+    }
+
+    Ok(sent_request)
+}
+
+/// The voice target id that .whisper and .shout (re-)register.
+const COMMAND_VOICE_TARGET: u8 = 1;
+
+/**
+ * Where our voice goes, as set by .whisper, .shout and .talk.
+ */
+struct VoiceTarget {
+    /// The voice target id, shared with the task that sends our voice.
+    id: Arc<AtomicU8>,
+    /// What the last .whisper or .shout asked for, to register it again after reconnecting.
+    command: Option<VoiceCommand>,
+}
+
+impl VoiceTarget {
+    fn new() -> Self {
+        VoiceTarget {
+            id: Arc::new(AtomicU8::new(voice::NORMAL_TALKING)),
+            command: None,
+        }
+    }
+}
+
+/**
+ * A .whisper or .shout by user and channel names, which unlike sessions and
+ * channel ids stay the same when we reconnect.
+ */
+#[derive(Debug)]
+enum VoiceCommand {
+    Whisper(Vec<String>),
+    Shout(String),
+}
+
+/**
+ * Parse a comma-separated list of .shout targets.
+ */
+fn parse_shout_targets(
+    arg: &str,
+    server_state: &ServerState,
+) -> Result<Vec<VoiceRecipient>, String> {
+    arg.split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+        .map(|spec| parse_shout_target(spec, server_state))
+        .collect()
+}
+
+/**
+ * Parse a .shout target like `Lounge+children+links#group`: a channel path,
+ * optionally including its sub-channels and linked channels, and optionally
+ * restricted to the members of an ACL group.
+ */
+fn parse_shout_target(spec: &str, server_state: &ServerState) -> Result<VoiceRecipient, String> {
+    let (spec, group) = match spec.split_once('#') {
+        Some((spec, group)) => (spec, Some(group.trim().to_string())),
+        None => (spec, None),
+    };
+
+    let mut parts = spec.split('+').map(str::trim);
+    let path = parts.next().unwrap_or_default();
+
+    let Some(channel_id) = server_state.find_channel(path) else {
+        return Err(format!("Could not find channel {:?}", path));
+    };
+
+    let mut links = false;
+    let mut children = false;
+    for flag in parts {
+        match flag {
+            "links" => links = true,
+            "children" => children = true,
+            _ => return Err(format!("Unknown shout option {:?}", flag)),
+        }
+    }
+
+    Ok(VoiceRecipient::Channel {
+        channel_id,
+        links,
+        children,
+        group,
+    })
+}
+
+/**
+ * Whether a user may use admin commands, going by their certificate hash.
+ */
+fn is_admin(cfg: &Config, user: &state::User) -> bool {
+    match (&cfg.admins, &user.hash) {
+        (Some(admins), Some(hash)) => admins.iter().any(|admin| admin.eq_ignore_ascii_case(hash)),
+        _ => false,
+    }
+}
+
+/**
+ * Move to our channel and put back the comment and mute/deaf flags we had
+ * before reconnecting. On the first connection, this joins the configured channel.
+ */
+async fn restore_presence(
+    msg_sender: &mpsc::Sender<MumbleMsg>,
+    cfg: &Config,
+    server_state: &ServerState,
+    session: u32,
+) -> anyhow::Result<()> {
+    // The connection authenticated with the configured tokens, so send any added since.
+    if let Some(tokens) = &cfg.tokens {
+        net::send_tokens(msg_sender, tokens).await?;
+    }
+
+    let previous = server_state.previous_presence();
+
+    let channel_path = previous
+        .map(|presence| presence.channel_path.as_str())
+        .or(cfg.channel.as_deref());
+
+    let channel_id = match channel_path {
+        Some(path) => {
+            let channel_id = server_state.find_channel(path);
+            if channel_id.is_none() {
+                warn!("Channel {:?} does not exist.", path);
+            }
+            channel_id
+        }
+        None => None,
+    };
+
+    if let Some(channel_id) = channel_id {
+        info!(
+            "Joining channel {:?}",
+            server_state.channel_path(channel_id)
+        );
+    }
+
+    let msg = mumble_proto::UserState {
+        session: Some(session),
+        channel_id,
+        comment: previous.and_then(|presence| presence.comment.clone()),
+        self_mute: previous.map(|presence| presence.self_mute),
+        self_deaf: previous.map(|presence| presence.self_deaf),
+        ..Default::default()
+    };
+
+    if msg.channel_id.is_some() || previous.is_some() {
+        msg_sender.send(MumbleMsg::UserState(msg)).await?;
     }
 
     Ok(())
 }
 
+/**
+ * Register the voice target of the last .whisper or .shout again, as the
+ * server forgets it when we disconnect. Whoever is gone is left out; if
+ * nobody is left, we keep talking to our channel.
+ */
+async fn restore_voice_target(
+    msg_sender: &mpsc::Sender<MumbleMsg>,
+    server_state: &ServerState,
+    voice_target: &AtomicU8,
+    command: &VoiceCommand,
+) -> anyhow::Result<()> {
+    let recipients = match command {
+        VoiceCommand::Whisper(names) => names
+            .iter()
+            .filter_map(|name| server_state.find_user(name))
+            .map(|user| VoiceRecipient::User(user.session))
+            .collect(),
+        VoiceCommand::Shout(arg) => parse_shout_targets(arg, server_state).unwrap_or_default(),
+    };
+
+    if recipients.is_empty() {
+        warn!(
+            "Could not restore voice target {:?}, talking to our channel.",
+            command
+        );
+        return Ok(());
+    }
+
+    net::set_voice_target(msg_sender, COMMAND_VOICE_TARGET, &recipients).await?;
+    voice_target.store(COMMAND_VOICE_TARGET, Ordering::Relaxed);
+
+    Ok(())
+}
+
+/**
+ * Register our certificate as a user on the server, unless it already is.
+ */
+async fn register_self(
+    msg_sender: &mpsc::Sender<MumbleMsg>,
+    server_state: &ServerState,
+    session: u32,
+) -> anyhow::Result<()> {
+    if server_state
+        .user(session)
+        .is_some_and(|user| user.user_id.is_some())
+    {
+        return Ok(());
+    }
+
+    info!("Registering ourselves on the server.");
+
+    let msg = mumble_proto::UserState {
+        session: Some(session),
+        user_id: Some(0),
+        ..Default::default()
+    };
+
+    msg_sender.send(MumbleMsg::UserState(msg)).await?;
+
+    Ok(())
+}
+
+const DEFAULT_DUCK_ATTACK_MS: u64 = 100;
+const DEFAULT_DUCK_RELEASE_MS: u64 = 800;
+
+/// How often we check whether anyone in our channel is talking, for ducking.
+const SPEAKERS_CHECK_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Permission errors arriving this long after a command are not attributed to it.
+const COMMAND_ERROR_WINDOW: Duration = Duration::from_secs(5);
+
+const ENTER_PERMISSION: u32 = 0x4;
+
+/**
+ * A request a command sent to the server on behalf of a user, so that a
+ * permission error about it can be reported to them.
+ */
+#[derive(Debug, Clone, Copy)]
+struct CommandRequest {
+    actor: u32,
+    sent: Instant,
+    /// The user the request was about.
+    session: u32,
+    /// The channel the request was about.
+    channel_id: u32,
+    /// The permission it needed.
+    permission: u32,
+}
+
+impl CommandRequest {
+    /**
+     * Moving `session` into `channel_id`, if someone asked for it.
+     */
+    fn join(actor: Option<u32>, session: u32, channel_id: u32) -> Option<Self> {
+        Some(CommandRequest {
+            actor: actor?,
+            sent: Instant::now(),
+            session,
+            channel_id,
+            permission: ENTER_PERMISSION,
+        })
+    }
+
+    /**
+     * Whether `denied` is the server refusing this request.
+     */
+    fn refused_by(&self, denied: &mumble_proto::PermissionDenied) -> bool {
+        use mumble_proto::permission_denied::DenyType;
+
+        let about_this = self.sent.elapsed() < COMMAND_ERROR_WINDOW
+            && denied.session.is_none_or(|session| session == self.session)
+            && denied
+                .channel_id
+                .is_none_or(|channel_id| channel_id == self.channel_id);
+
+        about_this
+            && match denied.r#type() {
+                DenyType::Permission => denied.permission == Some(self.permission),
+                DenyType::ChannelFull => true,
+                _ => false,
+            }
+    }
+}
+
+async fn report_permission_denied(
+    denied: &mumble_proto::PermissionDenied,
+    server_state: &ServerState,
+    msg_sender: &mpsc::Sender<MumbleMsg>,
+    last_request: Option<CommandRequest>,
+) -> anyhow::Result<()> {
+    let request = last_request.filter(|request| request.refused_by(denied));
+    let denied = error::PermissionDenied::new(denied, server_state);
+    warn!("{}", denied);
+
+    // Denials of anything else are only logged, rather than sent to the wrong person.
+    if let Some(request) = request {
+        net::send_text_message(
+            msg_sender,
+            TextTarget::User(request.actor),
+            denied.to_string(),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/**
+ * What the message handling keeps between messages.
+ */
+struct Commands {
+    server_state: ServerState,
+    voice_target: VoiceTarget,
+    recording: Option<recorder::Recording>,
+    /// The last request a command sent, so permission errors it causes can be reported.
+    last_request: Option<CommandRequest>,
+}
+
+impl Commands {
+    fn new(voice_target: VoiceTarget) -> Self {
+        Commands {
+            server_state: ServerState::new(),
+            voice_target,
+            recording: None,
+            last_request: None,
+        }
+    }
+
+    /**
+     * Bring the server state up to date with `msg`, report the permission
+     * errors the last command caused and run the commands it contains.
+     */
+    async fn handle(
+        &mut self,
+        msg: &MumbleMsg,
+        queue_sink: &mpsc::Sender<PlayerAction>,
+        msg_sender: &mpsc::Sender<MumbleMsg>,
+        cfg: &mut Config,
+    ) -> anyhow::Result<()> {
+        self.server_state.update(msg);
+
+        if let MumbleMsg::PermissionDenied(denied) = msg {
+            report_permission_denied(denied, &self.server_state, msg_sender, self.last_request)
+                .await?;
+        }
+
+        let sent = handle_message(
+            msg,
+            queue_sink,
+            msg_sender,
+            cfg,
+            &self.server_state,
+            &mut self.voice_target,
+            &mut self.recording,
+        )
+        .await?;
+        if sent.is_some() {
+            self.last_request = sent;
+        }
+
+        Ok(())
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     env_logger::init();
@@ -288,13 +1120,80 @@ async fn main() -> anyhow::Result<()> {
         .install_default()
         .unwrap();
 
-    let cfg = load_config("config.json").expect("config file");
+    let mut cfg = load_config("config.json").expect("config file");
+
+    let voice_target = VoiceTarget::new();
 
-    let (msg_sender, mut msg_receiver) = net::init(cfg.clone()).await?;
+    let (msg_sender, mut msg_receiver, mut connected) =
+        net::init(cfg.clone(), voice_target.id.clone()).await?;
 
     let (queue_sink, queue_source) = mpsc::channel(1);
 
-    let mut player_handle = tokio::spawn(player_task(queue_source, msg_sender.clone()));
+    let (talking, talking_source) = watch::channel(false);
+    let ducking = cfg.duck_level.map(|level| sound::Ducking {
+        talking: talking_source,
+        level: level.clamp(0.0, 1.0),
+        attack: Duration::from_millis(cfg.duck_attack_ms.unwrap_or(DEFAULT_DUCK_ATTACK_MS)),
+        release: Duration::from_millis(cfg.duck_release_ms.unwrap_or(DEFAULT_DUCK_RELEASE_MS)),
+    });
+    let ducking_enabled = ducking.is_some();
+
+    let encoding = sound::EncoderSettings::new(cfg.encoder.as_ref())?;
+    let buffering = sound::Buffering::new(cfg.buffer.as_ref())?;
+    let transition = sound::Transition::new(cfg.crossfade_ms, cfg.gapless);
+
+    let own_sessions = relay::OwnSessions::default();
+    let relayed = sound::Mixer::default();
+    let (relay_chat, mut relayed_chat) = mpsc::channel(16);
+
+    let mut relays = vec![];
+    for (i, relay_cfg) in cfg.relays.iter().flatten().enumerate() {
+        relays.push(
+            relay::start(
+                relay::MAIN_CONNECTION + 1 + i,
+                &cfg,
+                relay_cfg,
+                relayed.clone(),
+                relay_chat.clone(),
+                own_sessions.clone(),
+            )
+            .await?,
+        );
+    }
+
+    let (stream_events, stream_events_source) = mpsc::channel(8);
+    let streamer = sound::AudioSender::new(
+        msg_sender.clone(),
+        stream_events,
+        ducking,
+        encoding,
+        buffering,
+        transition,
+    );
+    if !relays.is_empty() {
+        streamer.relay(relayed).await;
+    }
+
+    let mut player_handle = tokio::spawn(player_task(
+        queue_source,
+        msg_sender.clone(),
+        cfg.avatar_file.clone(),
+        streamer,
+        stream_events_source,
+    ));
+
+    let mut commands = Commands::new(voice_target);
+
+    let (voice_sink, voice_source) = mpsc::channel(64);
+    let (voice_frames, _) = broadcast::channel(256);
+
+    tokio::spawn(voice::voice_task(voice_source, voice_frames.clone()));
+
+    // Only listen along when recording or relaying, so voice isn't decoded for nothing.
+    let always_listening = !relays.is_empty();
+    let mut heard_frames = always_listening.then(|| voice_frames.subscribe());
+    let mut speakers = voice::Speakers::default();
+    let mut speakers_tick = tokio::time::interval(SPEAKERS_CHECK_INTERVAL);
 
     'outer: loop {
         tokio::select! {
@@ -302,9 +1201,103 @@ async fn main() -> anyhow::Result<()> {
                 res.unwrap()?;
                 break 'outer;
             }
+            res = async { heard_frames.as_mut().unwrap().recv().await }, if heard_frames.is_some() => {
+                // Lagging behind only costs us some frames.
+                if let Ok(frame) = res {
+                    let server_state = &commands.server_state;
+                    let speaker = server_state
+                        .user(frame.session)
+                        .filter(|user| Some(user.channel_id) == server_state.own_channel());
+
+                    if let Some(speaker) = speaker {
+                        if let Some(failed) = commands.recording.take_if(|recording| !recording.heard(&frame, &speaker.name)) {
+                            let reason = match failed.stop().await {
+                                Ok(_) => String::from("the writer stopped"),
+                                Err(e) => e.to_string(),
+                            };
+                            warn!("Recording failed: {}", reason);
+
+                            if let Some(session) = server_state.own_session() {
+                                net::set_recording(&msg_sender, session, false).await?;
+                            }
+                            if let Some(channel) = server_state.own_channel() {
+                                net::send_text_message(
+                                    &msg_sender,
+                                    TextTarget::Channel(channel),
+                                    format!("Recording failed: {}", reason),
+                                ).await?;
+                            }
+                        }
+
+                        // Never send what one of our relays said back out.
+                        if !own_sessions.contains(&cfg.host, cfg.port, frame.session) {
+                            for relay in &relays {
+                                relay.heard(&frame);
+                            }
+                        }
+                    }
+                }
+            }
+            _ = speakers_tick.tick(), if ducking_enabled => {
+                let server_state = &commands.server_state;
+                let own_channel = server_state.own_channel();
+
+                let anyone_talking = speakers.anyone_talking(|session| {
+                    Some(session) != server_state.own_session()
+                        && server_state.user(session).map(|user| user.channel_id) == own_channel
+                });
+                talking.send_if_modified(|talking| std::mem::replace(talking, anyone_talking) != anyone_talking);
+            }
+            Some(text) = relayed_chat.recv() => {
+                if let Some(channel) = commands.server_state.own_channel() {
+                    net::send_text_message(&msg_sender, TextTarget::Channel(channel), text).await?;
+                }
+            }
+            res = connected.changed(), if connected.has_changed().is_ok() => {
+                if res.is_ok() && !*connected.borrow_and_update() {
+                    queue_sink.send(PlayerAction::Suspend).await?;
+                }
+            }
             msg = msg_receiver.recv() => {
-                if let Some(msg) = msg {
-                    handle_message(&msg, &queue_sink, &cfg).await?;
+                match msg {
+                    Some(MumbleMsg::UDPTunnel(packet)) => {
+                        if ducking_enabled {
+                            if let Ok(voice) = voice::VoicePacket::parse(&packet) {
+                                speakers.heard(&voice);
+                            }
+                        }
+
+                        // Voice is real-time, drop it rather than stall on a busy decoder.
+                        let _ = voice_sink.try_send(packet);
+                    }
+                    Some(msg) => {
+                        commands.handle(&msg, &queue_sink, &msg_sender, &mut cfg).await?;
+
+                        let server_state = &commands.server_state;
+                        match &msg {
+                            MumbleMsg::TextMessage(text) if !text.message.starts_with('.') => {
+                                if let Some(name) = relay::chat_sender(text, server_state, &own_sessions, &cfg.host, cfg.port) {
+                                    for relay in &relays {
+                                        relay.chat(name, &text.message).await;
+                                    }
+                                }
+                            }
+                            MumbleMsg::ServerSync(_) => {
+                                if let Some(session) = server_state.own_session() {
+                                    own_sessions.set(relay::MAIN_CONNECTION, &cfg.host, cfg.port, session);
+                                }
+                            }
+                            _ => {}
+                        }
+
+                        let listening = always_listening || commands.recording.is_some();
+                        if listening != heard_frames.is_some() {
+                            heard_frames = listening.then(|| voice_frames.subscribe());
+                        }
+                    }
+                    None => {
+                        anyhow::bail!("Connection to the server closed for good.");
+                    }
                 }
             }
         }
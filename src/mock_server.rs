@@ -0,0 +1,714 @@
+/*!
+ * A scriptable Mumble server for tests, speaking TLS on localhost.
+ *
+ * Tests accept the bot's connections one at a time, play the server side of
+ * the handshake (or reject it), and read back whatever the bot sends. Voice
+ * sent through the TCP tunnel is captured separately, with arrival times.
+ */
+
+use std::{
+    net::SocketAddr,
+    sync::{atomic::AtomicU8, Arc},
+    time::Duration,
+};
+
+use tokio::{
+    io::{ReadHalf, WriteHalf},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::{timeout, Instant},
+};
+use tokio_rustls::{
+    rustls::{self, pki_types::PrivateKeyDer, ServerConfig},
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+use prost::Message;
+
+use crate::{
+    mumble_proto::{self, reject::RejectType},
+    mumble_udp, net,
+    sound::{AudioSender, Buffering, EncoderSettings, SongEvent, Transition},
+    tls,
+    types::{Config, MumbleMsg, MumbleMsgSink, MumbleMsgSource},
+    voice::{self, VoicePacket},
+};
+
+/// How long to wait for the bot before failing a test.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct MockServer {
+    addr: SocketAddr,
+    fingerprint: String,
+    connections: mpsc::Receiver<TlsStream<TcpStream>>,
+    dir: tempfile::TempDir,
+}
+
+impl MockServer {
+    pub async fn start() -> Self {
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let cert = rcgen::CertificateParams::new(vec!["localhost".into()])
+            .unwrap()
+            .self_signed(&key_pair)
+            .unwrap();
+
+        let fingerprint = tls::fingerprint(cert.der());
+
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.der().clone()],
+                PrivateKeyDer::try_from(key_pair.serialize_der()).unwrap(),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (connections_wr, connections) = mpsc::channel(4);
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                if let Ok(stream) = acceptor.accept(stream).await {
+                    if connections_wr.send(stream).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        MockServer {
+            addr,
+            fingerprint,
+            connections,
+            dir: tempfile::tempdir().unwrap(),
+        }
+    }
+
+    /**
+     * A bot configuration for connecting to this server, trusting its
+     * certificate and keeping the generated client certificate in a temporary directory.
+     * Reconnection delays are kept short.
+     */
+    pub fn config(&self) -> Config {
+        let path = |name: &str| self.dir.path().join(name).to_string_lossy().into_owned();
+
+        serde_json::from_value(serde_json::json!({
+            "host": self.addr.ip().to_string(),
+            "port": self.addr.port(),
+            "username": "Mumblebot",
+            "server_fingerprint": self.fingerprint,
+            "cert_file": path("cert.pem"),
+            "key_file": path("key.pem"),
+            "reconnect_min_delay_secs": 0,
+            "reconnect_max_delay_secs": 0,
+            "rspotify_client_id": "",
+            "rspotify_client_secret": "",
+        }))
+        .unwrap()
+    }
+
+    /**
+     * Wait for the bot to connect.
+     */
+    pub async fn accept(&mut self) -> MockConnection {
+        let stream = timeout(TIMEOUT, self.connections.recv())
+            .await
+            .expect("bot connects in time")
+            .expect("listener still running");
+
+        let (rd, wr) = tokio::io::split(stream);
+
+        MockConnection {
+            rd,
+            wr,
+            voice: vec![],
+        }
+    }
+
+    /**
+     * Whether the bot connects again within the given time.
+     */
+    pub async fn reconnects_within(&mut self, duration: Duration) -> bool {
+        timeout(duration, self.connections.recv()).await.is_ok()
+    }
+}
+
+/**
+ * A voice packet captured from the TCP tunnel.
+ */
+pub struct CapturedVoice {
+    pub received: Instant,
+    pub packet: VoicePacket,
+}
+
+pub struct MockConnection {
+    rd: ReadHalf<TlsStream<TcpStream>>,
+    wr: WriteHalf<TlsStream<TcpStream>>,
+    voice: Vec<CapturedVoice>,
+}
+
+impl MockConnection {
+    pub async fn send(&mut self, msg: MumbleMsg) {
+        net::try_send_msg(&mut self.wr, &msg).await.unwrap();
+    }
+
+    /**
+     * Read a single message from the bot. None if the bot closed the connection.
+     */
+    async fn read(&mut self) -> Option<MumbleMsg> {
+        let msg = timeout(TIMEOUT, net::read_message(&mut self.rd))
+            .await
+            .expect("bot sends something in time");
+
+        match msg {
+            Ok(Some(msg)) => Some(msg),
+            Ok(None) => panic!("bot sent an unknown or malformed message"),
+            Err(_) => None,
+        }
+    }
+
+    fn capture(&mut self, packet: Vec<u8>) {
+        // Legacy packets from clients have no session field; put one
+        // in so they parse like the packets the server sends out.
+        let packet = match packet.first() {
+            Some(0) => packet,
+            _ => [&packet[..1], &[0], &packet[1..]].concat(),
+        };
+
+        self.voice.push(CapturedVoice {
+            received: Instant::now(),
+            packet: VoicePacket::parse(&packet).unwrap(),
+        });
+    }
+
+    /**
+     * The next message from the bot, capturing voice and skipping pings.
+     * None if the bot closed the connection.
+     */
+    pub async fn recv(&mut self) -> Option<MumbleMsg> {
+        loop {
+            match self.read().await? {
+                MumbleMsg::UDPTunnel(packet) => self.capture(packet),
+                MumbleMsg::Ping(_) => {}
+                msg => return Some(msg),
+            }
+        }
+    }
+
+    /**
+     * Skip messages until one matches.
+     */
+    pub async fn expect<T>(&mut self, mut matches: impl FnMut(MumbleMsg) -> Option<T>) -> T {
+        loop {
+            let msg = self.recv().await.expect("connection still open");
+            if let Some(value) = matches(msg) {
+                return value;
+            }
+        }
+    }
+
+    /**
+     * Wait for the bot's Version and Authenticate, returning the latter.
+     */
+    pub async fn expect_login(&mut self) -> mumble_proto::Authenticate {
+        self.expect(|msg| match msg {
+            MumbleMsg::Version(_) => Some(()),
+            _ => None,
+        })
+        .await;
+
+        self.expect(|msg| match msg {
+            MumbleMsg::Authenticate(auth) => Some(auth),
+            _ => None,
+        })
+        .await
+    }
+
+    /**
+     * Play the server side of a successful login: the channel tree as
+     * `(id, parent, name)`, the users as `(session, channel, name)` (including
+     * the bot itself) and finally ServerSync with the bot's session.
+     */
+    pub async fn sync(
+        &mut self,
+        session: u32,
+        channels: &[(u32, Option<u32>, &str)],
+        users: &[(u32, u32, &str)],
+    ) {
+        self.send(MumbleMsg::Version(mumble_proto::Version {
+            version_v1: Some(0x00010500),
+            version_v2: Some(0x0001000500000000),
+            release: Some("mock".into()),
+            ..Default::default()
+        }))
+        .await;
+
+        for &(id, parent, name) in channels {
+            self.send(MumbleMsg::ChannelState(mumble_proto::ChannelState {
+                channel_id: Some(id),
+                parent,
+                name: Some(name.into()),
+                ..Default::default()
+            }))
+            .await;
+        }
+
+        for &(user_session, channel_id, name) in users {
+            self.send(MumbleMsg::UserState(mumble_proto::UserState {
+                session: Some(user_session),
+                channel_id: Some(channel_id),
+                name: Some(name.into()),
+                ..Default::default()
+            }))
+            .await;
+        }
+
+        self.send(MumbleMsg::ServerSync(mumble_proto::ServerSync {
+            session: Some(session),
+            max_bandwidth: Some(72_000),
+            welcome_text: Some("Welcome to the mock server".into()),
+            ..Default::default()
+        }))
+        .await;
+    }
+
+    /**
+     * Send voice from `session` to the bot through the TCP tunnel.
+     */
+    pub async fn send_voice(&mut self, session: u32, frame_number: u64, opus_data: Vec<u8>) {
+        let audio = mumble_udp::Audio {
+            sender_session: session,
+            frame_number,
+            opus_data,
+            ..Default::default()
+        };
+
+        let mut packet = vec![0];
+        packet.extend(audio.encode_to_vec());
+        self.send(MumbleMsg::UDPTunnel(packet)).await;
+    }
+
+    pub async fn reject(&mut self, kind: RejectType, reason: &str) {
+        self.send(MumbleMsg::Reject(mumble_proto::Reject {
+            r#type: Some(kind as i32),
+            reason: Some(reason.into()),
+        }))
+        .await;
+    }
+
+    /**
+     * Receive until at least `count` voice packets were captured, and return them.
+     */
+    pub async fn voice(&mut self, count: usize) -> &[CapturedVoice] {
+        while self.voice.len() < count {
+            match self.read().await {
+                Some(MumbleMsg::UDPTunnel(packet)) => self.capture(packet),
+                Some(_) => {}
+                None => break,
+            }
+        }
+
+        &self.voice
+    }
+}
+
+/// Our session on the mock server, and the channels `(id, parent, name)` and
+/// users `(session, channel, name)` it has when tests sync.
+pub const BOT_SESSION: u32 = 1;
+pub const CHANNELS: &[(u32, Option<u32>, &str)] = &[(0, None, "Root"), (1, Some(0), "Lounge")];
+pub const USERS: &[(u32, u32, &str)] = &[(BOT_SESSION, 0, "Mumblebot"), (2, 0, "alice")];
+
+/**
+ * Connect the bot, leaving its messages to the test.
+ */
+pub async fn start_bot(cfg: Config) -> (MumbleMsgSink, MumbleMsgSource) {
+    let voice_target = Arc::new(AtomicU8::new(voice::NORMAL_TALKING));
+    let (msg_sender, msg_receiver, _) = net::init(cfg, voice_target).await.unwrap();
+    (msg_sender, msg_receiver)
+}
+
+/**
+ * Run the bot's message handling like `main` does, without a player.
+ */
+pub async fn start_bot_with_commands(cfg: Config) -> MumbleMsgSink {
+    let voice_target = crate::VoiceTarget::new();
+    let (msg_sender, mut msg_receiver, _) = net::init(cfg.clone(), voice_target.id.clone())
+        .await
+        .unwrap();
+
+    let (queue_sink, mut queue_source) = mpsc::channel(16);
+    tokio::spawn(async move { while queue_source.recv().await.is_some() {} });
+
+    let sender = msg_sender.clone();
+    tokio::spawn(async move {
+        let mut cfg = cfg;
+        let mut commands = crate::Commands::new(voice_target);
+
+        while let Some(msg) = msg_receiver.recv().await {
+            commands
+                .handle(&msg, &queue_sink, &sender, &mut cfg)
+                .await
+                .unwrap();
+        }
+    });
+
+    msg_sender
+}
+
+/**
+ * Skip the bot's messages up to the next ServerSync.
+ */
+pub async fn next_server_sync(source: &mut MumbleMsgSource) -> mumble_proto::ServerSync {
+    loop {
+        let msg = timeout(TIMEOUT, source.recv()).await.unwrap().unwrap();
+        if let MumbleMsg::ServerSync(sync) = msg {
+            return sync;
+        }
+    }
+}
+
+/**
+ * Connect a bot and sync it, then give it an `AudioSender` with the given
+ * settings. The server is returned too, as it must outlive the connection.
+ */
+pub async fn connected_sender(
+    encoding: EncoderSettings,
+    buffering: Buffering,
+    transition: Transition,
+) -> (
+    MockServer,
+    MockConnection,
+    AudioSender,
+    mpsc::Receiver<SongEvent>,
+) {
+    let mut server = MockServer::start().await;
+    let (sink, mut source) = start_bot(server.config()).await;
+
+    let mut conn = server.accept().await;
+    conn.expect_login().await;
+    conn.sync(BOT_SESSION, CHANNELS, USERS).await;
+    next_server_sync(&mut source).await;
+
+    // A single slot, so the send task has to wait for each event to be taken, as for a busy player.
+    let (events_wr, events) = mpsc::channel(1);
+    let streamer = AudioSender::new(sink, events_wr, None, encoding, buffering, transition);
+
+    (server, conn, streamer, events)
+}
+
+/// `ms` milliseconds of a quiet stereo tone.
+pub fn tone(ms: usize) -> Vec<i16> {
+    (0..48 * ms)
+        .flat_map(|i| {
+            let sample = ((i as f64 / 48.0).sin() * 1000.0) as i16;
+            [sample, sample]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::TextTarget;
+
+    #[tokio::test]
+    async fn connects_and_syncs() {
+        let mut server = MockServer::start().await;
+        let (_sink, mut source) = start_bot(server.config()).await;
+
+        let mut conn = server.accept().await;
+        let auth = conn.expect_login().await;
+        assert_eq!(auth.username.as_deref(), Some("Mumblebot"));
+
+        conn.sync(BOT_SESSION, CHANNELS, USERS).await;
+
+        let sync = next_server_sync(&mut source).await;
+        assert_eq!(sync.session, Some(BOT_SESSION));
+    }
+
+    #[tokio::test]
+    async fn fatal_reject_stops_reconnecting() {
+        let mut server = MockServer::start().await;
+        let (_sink, mut source) = start_bot(server.config()).await;
+
+        let mut conn = server.accept().await;
+        conn.expect_login().await;
+        conn.reject(RejectType::WrongServerPw, "nope").await;
+
+        // The bot gives up, closing its message channel...
+        while timeout(TIMEOUT, source.recv()).await.unwrap().is_some() {}
+
+        // ...and doesn't come back.
+        assert!(!server.reconnects_within(Duration::from_millis(500)).await);
+    }
+
+    #[tokio::test]
+    async fn username_in_use_retries_with_suffix() {
+        let mut server = MockServer::start().await;
+        let (_sink, _source) = start_bot(server.config()).await;
+
+        let mut conn = server.accept().await;
+        conn.expect_login().await;
+        conn.reject(RejectType::UsernameInUse, "taken").await;
+
+        let mut conn = server.accept().await;
+        let auth = conn.expect_login().await;
+        assert_eq!(auth.username.as_deref(), Some("Mumblebot_1"));
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_disconnect() {
+        let mut server = MockServer::start().await;
+        let (_sink, mut source) = start_bot(server.config()).await;
+
+        let mut conn = server.accept().await;
+        conn.expect_login().await;
+        conn.sync(BOT_SESSION, CHANNELS, USERS).await;
+        next_server_sync(&mut source).await;
+
+        drop(conn);
+
+        let mut conn = server.accept().await;
+        let auth = conn.expect_login().await;
+        assert_eq!(auth.username.as_deref(), Some("Mumblebot"));
+
+        conn.sync(BOT_SESSION + 1, CHANNELS, USERS).await;
+        let sync = next_server_sync(&mut source).await;
+        assert_eq!(sync.session, Some(BOT_SESSION + 1));
+    }
+
+    #[tokio::test]
+    async fn messages_sent_while_disconnected_are_dropped() {
+        let mut server = MockServer::start().await;
+        let (sink, mut source) = start_bot(server.config()).await;
+
+        let send_stale = async |count| {
+            // None of it waits for the server, even far more than the channel holds.
+            timeout(TIMEOUT, async {
+                for i in 0..count {
+                    net::send_text_message(&sink, TextTarget::User(2), format!("stale {}", i))
+                        .await
+                        .unwrap();
+                }
+            })
+            .await
+            .unwrap();
+        };
+
+        let mut conn = server.accept().await;
+        conn.expect_login().await;
+        conn.sync(BOT_SESSION, CHANNELS, USERS).await;
+        next_server_sync(&mut source).await;
+
+        drop(conn);
+        send_stale(100).await;
+
+        // Until ServerSync, the new connection is no place for them either.
+        let mut conn = server.accept().await;
+        conn.expect_login().await;
+        send_stale(100).await;
+        conn.sync(BOT_SESSION, CHANNELS, USERS).await;
+        next_server_sync(&mut source).await;
+
+        net::send_text_message(&sink, TextTarget::User(2), "fresh")
+            .await
+            .unwrap();
+        let text = conn
+            .expect(|msg| match msg {
+                MumbleMsg::TextMessage(text) => Some(text),
+                _ => None,
+            })
+            .await;
+        assert_eq!(text.message, "fresh");
+    }
+
+    #[tokio::test]
+    async fn join_command_moves_the_bot() {
+        let mut server = MockServer::start().await;
+        let _sink = start_bot_with_commands(server.config()).await;
+
+        let mut conn = server.accept().await;
+        conn.expect_login().await;
+        conn.sync(BOT_SESSION, CHANNELS, USERS).await;
+
+        conn.send(MumbleMsg::TextMessage(mumble_proto::TextMessage {
+            actor: Some(2),
+            channel_id: vec![0],
+            message: ".join lounge".into(),
+            ..Default::default()
+        }))
+        .await;
+
+        let state = conn
+            .expect(|msg| match msg {
+                MumbleMsg::UserState(state) => Some(state),
+                _ => None,
+            })
+            .await;
+        assert_eq!(state.session, Some(BOT_SESSION));
+        assert_eq!(state.channel_id, Some(1));
+    }
+
+    #[tokio::test]
+    async fn permission_errors_go_to_whoever_caused_them() {
+        use mumble_proto::permission_denied::DenyType;
+
+        let mut server = MockServer::start().await;
+        let _sink = start_bot_with_commands(server.config()).await;
+
+        let mut conn = server.accept().await;
+        conn.expect_login().await;
+        conn.sync(BOT_SESSION, CHANNELS, USERS).await;
+
+        conn.send(MumbleMsg::TextMessage(mumble_proto::TextMessage {
+            actor: Some(2),
+            channel_id: vec![0],
+            message: ".join lounge".into(),
+            ..Default::default()
+        }))
+        .await;
+        conn.expect(|msg| match msg {
+            MumbleMsg::UserState(state) => state.channel_id,
+            _ => None,
+        })
+        .await;
+
+        // Something else the bot did was refused, which is nobody's doing.
+        let mut unrelated = mumble_proto::PermissionDenied::default();
+        unrelated.set_type(DenyType::TextTooLong);
+        conn.send(MumbleMsg::PermissionDenied(unrelated)).await;
+
+        let mut denied = mumble_proto::PermissionDenied {
+            permission: Some(0x4),
+            channel_id: Some(1),
+            session: Some(BOT_SESSION),
+            ..Default::default()
+        };
+        denied.set_type(DenyType::Permission);
+        conn.send(MumbleMsg::PermissionDenied(denied)).await;
+
+        let reply = conn
+            .expect(|msg| match msg {
+                MumbleMsg::TextMessage(text) => Some(text),
+                _ => None,
+            })
+            .await;
+        assert_eq!(reply.session, [2]);
+        assert!(reply.message.contains("Enter"), "{:?}", reply.message);
+    }
+
+    #[tokio::test]
+    async fn empty_access_tokens_are_refused() {
+        let mut server = MockServer::start().await;
+        let mut cfg = server.config();
+        cfg.admins = Some(vec!["abcdef".into()]);
+        let _sink = start_bot_with_commands(cfg).await;
+
+        let mut conn = server.accept().await;
+        conn.expect_login().await;
+        conn.sync(BOT_SESSION, CHANNELS, USERS).await;
+        conn.send(MumbleMsg::UserState(mumble_proto::UserState {
+            session: Some(2),
+            hash: Some("ABCDEF".into()),
+            ..Default::default()
+        }))
+        .await;
+
+        for (command, reply) in [(".token add   ", "Usage"), (".token add abc", "added")] {
+            conn.send(MumbleMsg::TextMessage(mumble_proto::TextMessage {
+                actor: Some(2),
+                session: vec![BOT_SESSION],
+                message: command.into(),
+                ..Default::default()
+            }))
+            .await;
+
+            // Only the valid token reaches the server, before the reply.
+            let mut tokens = None;
+            let text = conn
+                .expect(|msg| match msg {
+                    MumbleMsg::Authenticate(auth) => {
+                        tokens = Some(auth.tokens);
+                        None
+                    }
+                    MumbleMsg::TextMessage(text) => Some(text),
+                    _ => None,
+                })
+                .await;
+            assert!(text.message.contains(reply), "{:?}", text.message);
+            assert_eq!(tokens.is_some(), reply == "added", "{:?}", tokens);
+            if let Some(tokens) = tokens {
+                assert_eq!(tokens, ["abc"]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn commands_reply_where_they_came_from() {
+        let mut server = MockServer::start().await;
+        let _sink = start_bot_with_commands(server.config()).await;
+
+        let mut conn = server.accept().await;
+        conn.expect_login().await;
+        conn.sync(BOT_SESSION, CHANNELS, USERS).await;
+
+        conn.send(MumbleMsg::TextMessage(mumble_proto::TextMessage {
+            actor: Some(2),
+            session: vec![BOT_SESSION],
+            message: ".who".into(),
+            ..Default::default()
+        }))
+        .await;
+
+        let reply = conn
+            .expect(|msg| match msg {
+                MumbleMsg::TextMessage(text) => Some(text),
+                _ => None,
+            })
+            .await;
+        assert_eq!(reply.session, [2]);
+        assert!(reply.message.contains("alice"), "{:?}", reply.message);
+    }
+
+    #[tokio::test]
+    async fn audio_is_sent_in_real_time() {
+        const FRAMES: usize = 20;
+
+        let (_server, mut conn, streamer, mut finish_rd) = connected_sender(
+            EncoderSettings::default(),
+            Buffering::default(),
+            Transition::default(),
+        )
+        .await;
+
+        // 200 ms of a quiet stereo tone, in one chunk
+        let (pcm_wr, pcm_rd) = mpsc::channel(1);
+        pcm_wr.send(tone(FRAMES * 10)).await.unwrap();
+        drop(pcm_wr);
+
+        let started = Instant::now();
+        streamer.start(pcm_rd).await.unwrap();
+
+        let voice = conn.voice(FRAMES).await;
+        assert_eq!(voice.len(), FRAMES);
+
+        for (i, captured) in voice.iter().enumerate() {
+            assert_eq!(captured.packet.seq_nr, i as u64);
+            assert_eq!(captured.packet.target, voice::NORMAL_TALKING);
+            assert!(!captured.packet.payload.is_empty());
+        }
+
+        // One 10 ms frame per tick, the first one right away. Packets may be
+        // read in bursts, so only the last arrival time says something.
+        let elapsed = voice[FRAMES - 1].received - started;
+        assert!(elapsed >= Duration::from_millis(180), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+
+        timeout(TIMEOUT, finish_rd.recv()).await.unwrap();
+    }
+}
@@ -1,89 +1,244 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use log::{debug, info, trace, warn};
+use log::{debug, error, info, trace, warn};
 use num_traits::FromPrimitive;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
-    sync::mpsc,
+    sync::{mpsc, oneshot, watch, Mutex},
     time::sleep,
 };
 
-use tokio_rustls::rustls::pki_types::pem::PemObject;
-use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
-use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::rustls::pki_types::ServerName;
 use tokio_rustls::TlsConnector;
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    mumble_proto,
+    error::Rejected,
+    mumble_proto::{self, reject::RejectType},
+    state::ServerLimits,
+    text, tls,
     types::{
-        self, Config, MumbleMsg, MumbleMsgSink, MumbleMsgSource, MumbleType, ReadStream,
-        WriteStream,
+        Config, MumbleMsg, MumbleMsgSink, MumbleMsgSource, MumbleType, ReadStream, TextTarget,
+        VoiceRecipient, WriteStream,
     },
+    udp::{self, UdpTransport},
+    voice::{self, VoiceFormat},
 };
 
-async fn read_message(stream: &mut ReadStream) -> anyhow::Result<MumbleMsg> {
-    loop {
-        let tag = MumbleType::from_u16(stream.read_u16().await?).expect("valid type tag");
+/// Largest message we accept from the server, the same limit the Mumble client uses.
+const MAX_FRAME_SIZE: u32 = 0x7F_FFFF;
 
-        let len = stream.read_u32().await?;
+/// Highest number tried after our username when it is in use.
+const MAX_USERNAME_SUFFIX: u32 = 9;
 
-        let mut buf = vec![0; len as usize];
-        stream.read_exact(&mut buf).await?;
+/// Reconnect delays grow from at least this, even with no minimum delay.
+const MIN_BACKOFF_STEP: Duration = Duration::from_millis(100);
 
-        if tag == MumbleType::UDPTunnel {
-            continue;
-        }
+/**
+ * Read one message from the wire.
+ * Messages of an unknown type or that fail to decode are skipped, returning None;
+ * only errors that leave the stream out of sync are returned as errors.
+ */
+pub async fn read_message<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> anyhow::Result<Option<MumbleMsg>> {
+    let tag = stream.read_u16().await?;
+    let len = stream.read_u32().await?;
+
+    if len > MAX_FRAME_SIZE {
+        anyhow::bail!("message of type {} is too large ({} bytes)", tag, len);
+    }
+
+    let mut buf = vec![0; len as usize];
+    stream.read_exact(&mut buf).await?;
+
+    let Some(tag) = MumbleType::from_u16(tag) else {
+        debug!("Skipping message of unknown type {} ({} bytes)", tag, len);
+        return Ok(None);
+    };
 
-        break Ok(MumbleMsg::from_tagged_data(tag, &buf)?);
+    match MumbleMsg::from_tagged_data(tag, &buf) {
+        Ok(msg) => Ok(Some(msg)),
+        Err(e) => {
+            warn!("Skipping malformed {:?} message: {:?}", tag, e);
+            Ok(None)
+        }
     }
 }
 
-async fn send_version(out: &mut MumbleMsgSink) -> anyhow::Result<()> {
+async fn send_version(out: &mut WriteStream) -> anyhow::Result<()> {
     let our_version = mumble_proto::Version {
         release: Some("MumbleBot".into()),
         os: Some("Linux".into()),
-        // we report version 1.4.0
-        version_v2: Some(0x0001000400000000),
+        // we report version 1.5.0, so 1.5 servers talk protobuf UDP packets to us
+        version_v1: Some(0x00010500),
+        version_v2: Some(0x0001000500000000),
         ..Default::default()
     };
 
-    out.send(MumbleMsg::Version(our_version)).await?;
-
-    Ok(())
+    try_send_msg(out, &MumbleMsg::Version(our_version)).await
 }
 
-async fn send_auth(out: &mut MumbleMsgSink, cfg: &Config) -> anyhow::Result<()> {
+async fn send_auth(out: &mut WriteStream, cfg: &Config, username: &str) -> anyhow::Result<()> {
     let our_auth = mumble_proto::Authenticate {
         client_type: Some(1), // BOT
         opus: Some(true),
-        username: Some(cfg.username.clone()),
+        username: Some(username.into()),
+        password: cfg.password.clone(),
+        tokens: cfg.tokens.clone().unwrap_or_default(),
+        ..Default::default()
+    };
+
+    try_send_msg(out, &MumbleMsg::Authenticate(our_auth)).await
+}
+
+/**
+ * Replace our access tokens. The server re-checks channel permissions with the new list.
+ */
+pub async fn send_tokens(out: &MumbleMsgSink, tokens: &[String]) -> anyhow::Result<()> {
+    let msg = mumble_proto::Authenticate {
+        tokens: tokens.to_vec(),
         ..Default::default()
     };
 
-    out.send(MumbleMsg::Authenticate(our_auth)).await?;
+    out.send(MumbleMsg::Authenticate(msg)).await?;
 
     Ok(())
 }
 
-pub async fn send_text_message(out: &MumbleMsgSink, text: impl Into<String>) -> anyhow::Result<()> {
-    let msg = mumble_proto::TextMessage {
-        channel_id: vec![0],
+pub async fn send_text_message(
+    out: &MumbleMsgSink,
+    target: TextTarget,
+    text: impl Into<String>,
+) -> anyhow::Result<()> {
+    let mut msg = mumble_proto::TextMessage {
         message: text.into(),
         ..Default::default()
     };
 
+    match target {
+        TextTarget::Channel(channel_id) => msg.channel_id.push(channel_id),
+        TextTarget::User(session) => msg.session.push(session),
+        TextTarget::Tree(channel_id) => msg.tree_id.push(channel_id),
+    }
+
     out.send(MumbleMsg::TextMessage(msg)).await?;
 
     Ok(())
 }
 
+pub async fn join_channel(
+    out: &MumbleMsgSink,
+    session: u32,
+    channel_id: u32,
+) -> anyhow::Result<()> {
+    let msg = mumble_proto::UserState {
+        session: Some(session),
+        channel_id: Some(channel_id),
+        ..Default::default()
+    };
+
+    out.send(MumbleMsg::UserState(msg)).await?;
+
+    Ok(())
+}
+
+/**
+ * Show or hide the recording indicator next to our name.
+ */
+pub async fn set_recording(
+    out: &MumbleMsgSink,
+    session: u32,
+    recording: bool,
+) -> anyhow::Result<()> {
+    let msg = mumble_proto::UserState {
+        session: Some(session),
+        recording: Some(recording),
+        ..Default::default()
+    };
+
+    out.send(MumbleMsg::UserState(msg)).await?;
+
+    Ok(())
+}
+
+/**
+ * Register a voice target, which voice packets can then be addressed to by its id (1-30).
+ */
+pub async fn set_voice_target(
+    out: &MumbleMsgSink,
+    id: u8,
+    recipients: &[VoiceRecipient],
+) -> anyhow::Result<()> {
+    let targets = recipients
+        .iter()
+        .map(|recipient| match recipient {
+            VoiceRecipient::User(session) => mumble_proto::voice_target::Target {
+                session: vec![*session],
+                ..Default::default()
+            },
+            VoiceRecipient::Channel {
+                channel_id,
+                links,
+                children,
+                group,
+            } => mumble_proto::voice_target::Target {
+                channel_id: Some(*channel_id),
+                links: Some(*links),
+                children: Some(*children),
+                group: group.clone(),
+                ..Default::default()
+            },
+        })
+        .collect();
+
+    let msg = mumble_proto::VoiceTarget {
+        id: Some(id as u32),
+        targets,
+    };
+
+    out.send(MumbleMsg::VoiceTarget(msg)).await?;
+
+    Ok(())
+}
+
+/**
+ * How a connection ended, as seen by the receiver task.
+ */
+#[derive(Debug, Default)]
+struct SessionEnd {
+    /// The server rejected our connection attempt.
+    rejection: Option<Rejected>,
+    /// We got as far as ServerSync, so the connection had been usable.
+    synced: bool,
+}
+
+/**
+ * Task that reads MumbleMsgs from the wire and passes them on.
+ * On ServerSync, `synced` is sent a channel to close once the connection is
+ * ready for our messages; the ServerSync is only passed on after that.
+ * The task returns the MumbleMsg sender channel on exiting so it can be reused,
+ * along with how the connection ended.
+ */
 async fn receiver_task(
     mut stream: ReadStream,
     channel: mpsc::Sender<MumbleMsg>,
+    udp: Arc<UdpTransport>,
+    limits: Arc<Mutex<ServerLimits>>,
+    synced: oneshot::Sender<oneshot::Sender<()>>,
     ct: CancellationToken,
-) -> mpsc::Sender<MumbleMsg> {
+) -> (mpsc::Sender<MumbleMsg>, SessionEnd) {
+    let mut end = SessionEnd::default();
+    let mut synced = Some(synced);
+
     loop {
         let msg = tokio::select! {
             msg = read_message(&mut stream) => {
@@ -94,8 +249,48 @@ async fn receiver_task(
             }
         };
         let res = match msg {
-            Ok(msg) => {
+            Ok(None) => continue,
+            Ok(Some(MumbleMsg::UDPTunnel(packet))) => {
+                trace!(target: "mumblebot::net::voice",
+                    "Received tunneled voice packet, length {}",
+                    packet.len()
+                );
+                channel.send(MumbleMsg::UDPTunnel(packet)).await
+            }
+            Ok(Some(msg)) => {
                 debug!("Received message from server: {:?}", msg);
+
+                match &msg {
+                    MumbleMsg::CryptSetup(crypt_setup) => {
+                        if let Err(e) = udp.handle_crypt_setup(crypt_setup).await {
+                            warn!("Invalid CryptSetup from server: {:?}", e);
+                        }
+                    }
+                    MumbleMsg::Version(version) => {
+                        udp.set_format(VoiceFormat::for_server(version)).await;
+                    }
+                    MumbleMsg::Reject(reject) => {
+                        end.rejection = Some(Rejected::new(reject));
+                    }
+                    MumbleMsg::ServerSync(_) => {
+                        end.synced = true;
+                        limits.lock().await.update(&msg);
+
+                        // Anything sent in reply to the ServerSync must not be
+                        // thrown away with the stale messages.
+                        if let Some(synced) = synced.take() {
+                            let (ready_wr, ready_rd) = oneshot::channel();
+                            if synced.send(ready_wr).is_ok() {
+                                let _ = ready_rd.await;
+                            }
+                        }
+                    }
+                    MumbleMsg::ServerConfig(_) => {
+                        limits.lock().await.update(&msg);
+                    }
+                    _ => {}
+                }
+
                 channel.send(msg).await
             }
             Err(e) => {
@@ -104,38 +299,29 @@ async fn receiver_task(
             }
         };
 
-        if res.is_err() {
+        if res.is_err() || end.rejection.is_some() {
             break;
         }
     }
 
-    channel
+    (channel, end)
 }
 
-async fn try_send_voice_data(
-    stream: &mut WriteStream,
-    seq_nr: u64,
-    data: &[u8],
+async fn try_send_voice_data<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    packet: &[u8],
 ) -> anyhow::Result<()> {
-    let seq_nr_encoded = types::varint_encode(seq_nr);
-    let len_encoded = types::varint_encode(data.len() as u64);
-
-    let total_packet_len = 1 + seq_nr_encoded.len() + len_encoded.len() + data.len();
-
     stream.write_u16(MumbleType::UDPTunnel as u16).await?;
-    stream.write_u32(total_packet_len as u32).await?;
-
-    stream.write_u8(128).await?; // type + target
-
-    stream.write_all(&seq_nr_encoded).await?;
-    stream.write_all(&len_encoded).await?;
-
-    stream.write_all(data).await?;
+    stream.write_u32(packet.len() as u32).await?;
+    stream.write_all(packet).await?;
 
     Ok(())
 }
 
-async fn try_send_msg(stream: &mut WriteStream, msg: &MumbleMsg) -> anyhow::Result<()> {
+pub async fn try_send_msg<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    msg: &MumbleMsg,
+) -> anyhow::Result<()> {
     let tag = msg.tag();
     let data = msg.as_data();
     debug!("Sending message: {:?} {:?}", tag, data);
@@ -150,21 +336,26 @@ async fn try_send_msg(stream: &mut WriteStream, msg: &MumbleMsg) -> anyhow::Resu
 
 /**
  * Task that sends encoded MumbleMsgs over the wire.
+ * Voice is addressed to the voice target currently stored in `voice_target`,
+ * and text messages are adapted to the server's limits.
  * The task returns the MumbleMsg receiver channel on exiting so it can be reused.
  */
 async fn sender_task(
     mut stream: WriteStream,
     mut channel: mpsc::Receiver<MumbleMsg>,
+    udp: Arc<UdpTransport>,
+    voice_target: Arc<AtomicU8>,
+    limits: Arc<Mutex<ServerLimits>>,
     ct: CancellationToken,
 ) -> mpsc::Receiver<MumbleMsg> {
     let mut packet_sequence_nr: u64 = 0;
 
     let mut ping_interval = tokio::time::interval(Duration::from_secs(15));
 
-    loop {
+    'outer: loop {
         let msg = tokio::select! {
             _ = ping_interval.tick() => {
-                MumbleMsg::Ping(mumble_proto::Ping::default())
+                MumbleMsg::Ping(udp.tcp_ping().await)
             }
             res = channel.recv() => {
                 ping_interval.reset();
@@ -185,12 +376,37 @@ async fn sender_task(
                 audio_data.len()
             );
 
-            let res = try_send_voice_data(&mut stream, packet_sequence_nr, &audio_data).await;
-            if res.is_err() {
-                break;
+            // An empty frame marks the end of what we were saying.
+            let packet = voice::encode_voice_packet(
+                udp.format().await,
+                voice_target.load(Ordering::Relaxed),
+                packet_sequence_nr,
+                &audio_data,
+                audio_data.is_empty(),
+            );
+
+            let sent_over_udp = udp.is_active().await && udp.send(&packet).await.is_ok();
+            if !sent_over_udp {
+                let res = try_send_voice_data(&mut stream, &packet).await;
+                if res.is_err() {
+                    break;
+                }
             }
 
-            packet_sequence_nr += 1;
+            packet_sequence_nr += voice::seq_frames(&audio_data);
+        } else if let MumbleMsg::TextMessage(text_message) = msg {
+            let parts = text::prepare_message(&text_message.message, &*limits.lock().await);
+
+            for part in parts {
+                let msg = MumbleMsg::TextMessage(mumble_proto::TextMessage {
+                    message: part,
+                    ..text_message.clone()
+                });
+
+                if try_send_msg(&mut stream, &msg).await.is_err() {
+                    break 'outer;
+                }
+            }
         } else {
             let res = try_send_msg(&mut stream, &msg).await;
             if res.is_err() {
@@ -205,88 +421,550 @@ async fn sender_task(
 /**
  * Set up a connection to the Mumble server.
  *
- * Returns the read and write halves of a TlsStream.
+ * Returns the read and write halves of a TlsStream, and the server address
+ * for the UDP voice channel.
  */
-async fn connect(server_name: &str, port: u16) -> anyhow::Result<(ReadStream, WriteStream)> {
-    let mut root_cert_store = RootCertStore::empty();
-    root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-
-    let cert_chain = vec![CertificateDer::from_pem_file("cert.pem")?];
-    let key_der = PrivateKeyDer::from_pem_file("key.pem")?;
-
-    let config = ClientConfig::builder()
-        .with_root_certificates(root_cert_store)
-        .with_client_auth_cert(cert_chain, key_der)?;
+async fn connect(cfg: &Config) -> anyhow::Result<(ReadStream, WriteStream, SocketAddr)> {
+    let config = tls::client_config(cfg)?;
     let connector = TlsConnector::from(Arc::new(config));
 
-    let dnsname = ServerName::try_from(String::from(server_name)).unwrap();
+    let dnsname = ServerName::try_from(cfg.host.clone())?;
 
-    let url_with_port = format!("{}:{}", server_name, port);
+    let url_with_port = format!("{}:{}", cfg.host, cfg.port);
     let stream = TcpStream::connect(url_with_port).await?;
+    let peer_addr = stream.peer_addr()?;
     let tls_stream = connector.connect(dnsname, stream).await?;
 
-    Ok(tokio::io::split(tls_stream))
+    let (rd, wr) = tokio::io::split(tls_stream);
+
+    Ok((rd, wr, peer_addr))
+}
+
+/**
+ * Jittered exponential backoff between connection attempts.
+ */
+struct Backoff {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    fn new(min: Duration, max: Duration) -> Self {
+        Backoff {
+            min,
+            max,
+            current: min,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.current = self.min;
+    }
+
+    /**
+     * The delay before the next attempt. Each call doubles the delay up to the
+     * maximum; the returned value is randomized between half and all of it, so
+     * several bots don't all hammer a restarting server at the same moment.
+     */
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current.max(MIN_BACKOFF_STEP) * 2).min(self.max);
+
+        delay / 2 + (delay / 2).mul_f64(rand::random::<f64>())
+    }
+
+    async fn wait(&mut self) {
+        let delay = self.next_delay();
+        info!("Reconnecting in {:.1}s...", delay.as_secs_f64());
+        sleep(delay).await
+    }
+}
+
+/**
+ * Wait for `until` while disconnected, throwing away whatever is sent to the
+ * server meanwhile, so that nobody sending blocks until we are back.
+ * Audio would be played late, and everything else refers to sessions and
+ * channels of the old connection.
+ */
+async fn drop_messages_until<T>(
+    channel: &mut mpsc::Receiver<MumbleMsg>,
+    until: impl std::future::Future<Output = T>,
+) -> T {
+    tokio::pin!(until);
+    let mut dropped = 0;
+
+    let res = loop {
+        // Empty the channel before each check of `until`, so that nothing
+        // sent before it completed is left behind.
+        tokio::select! {
+            biased;
+            Some(_) = channel.recv() => dropped += 1,
+            res = &mut until => break res,
+        }
+    };
+
+    if dropped > 0 {
+        debug!("Dropped {} messages sent while disconnected.", dropped);
+    }
+
+    res
 }
 
 async fn reconnect_task(
     cfg: Config,
     mut sender_rd: mpsc::Receiver<MumbleMsg>,
-    mut sender_wr: mpsc::Sender<MumbleMsg>,
+    sender_wr: mpsc::Sender<MumbleMsg>,
     mut receiver_wr: mpsc::Sender<MumbleMsg>,
+    connected: watch::Sender<bool>,
+    voice_target: Arc<AtomicU8>,
 ) {
-    let mut sender_handle;
-    let mut receiver_handle;
     let mut ct;
+    let mut end;
+
+    let mut username = cfg.username.clone();
+    let mut username_attempt = 0;
+
+    let mut backoff = Backoff::new(
+        Duration::from_secs(cfg.reconnect_min_delay_secs.unwrap_or(1)),
+        Duration::from_secs(cfg.reconnect_max_delay_secs.unwrap_or(60)),
+    );
 
     loop {
-        let (net_rd, net_wr) = loop {
-            match connect(&cfg.host, cfg.port).await {
+        let (net_rd, mut net_wr, peer_addr) = loop {
+            match drop_messages_until(&mut sender_rd, connect(&cfg)).await {
                 Ok(res) => break res,
                 Err(e) => {
-                    warn!("Failed to connect (error {:?}), waiting a minute...", e);
-                    sleep(Duration::from_mins(1)).await
+                    warn!("Failed to connect (error {:?})", e);
+                    drop_messages_until(&mut sender_rd, backoff.wait()).await
                 }
             }
         };
 
+        // The server forgets our voice targets when we disconnect.
+        voice_target.store(voice::NORMAL_TALKING, Ordering::Relaxed);
+
         ct = CancellationToken::new();
 
-        sender_handle = tokio::spawn(sender_task(net_wr, sender_rd, ct.child_token()));
+        let udp = Arc::new(UdpTransport::bind(peer_addr, sender_wr.clone()).await);
 
-        receiver_handle = tokio::spawn(receiver_task(net_rd, receiver_wr, ct.child_token()));
+        let limits = Arc::new(Mutex::new(ServerLimits::default()));
 
-        send_version(&mut sender_wr)
-            .await
-            .expect("able to put msg in channel");
+        tokio::spawn(udp::udp_task(
+            udp.clone(),
+            receiver_wr.clone(),
+            ct.child_token(),
+        ));
 
-        send_auth(&mut sender_wr, &cfg)
-            .await
-            .expect("able to put msg in channel");
+        // Log in before anything else goes out on the new connection.
+        let login = async {
+            send_version(&mut net_wr).await?;
+            send_auth(&mut net_wr, &cfg, &username).await
+        };
+        if let Err(e) = login.await {
+            warn!("Failed to log in (error {:?})", e);
+        }
 
-        (sender_rd, receiver_wr) = tokio::select! {
-            wr_chan = &mut sender_handle => {
-                ct.cancel();
-                let rd_chan = receiver_handle.await;
-                (wr_chan.expect("no panic in write task"), rd_chan.expect("no error in read task"))
-            },
-            rd_chan = &mut receiver_handle => {
+        let (synced_wr, synced_rd) = oneshot::channel();
+
+        let mut receiver_handle = tokio::spawn(receiver_task(
+            net_rd,
+            receiver_wr,
+            udp.clone(),
+            limits.clone(),
+            synced_wr,
+            ct.child_token(),
+        ));
+
+        // Until ServerSync, our sessions and channels are unknown on this
+        // connection, so whatever is sent meanwhile is stale too.
+        match drop_messages_until(&mut sender_rd, synced_rd).await {
+            Ok(ready) => {
+                let mut sender_handle = tokio::spawn(sender_task(
+                    net_wr,
+                    sender_rd,
+                    udp.clone(),
+                    voice_target.clone(),
+                    limits,
+                    ct.child_token(),
+                ));
+
+                // Let the ServerSync through, now that the stale messages are gone.
+                connected.send_replace(true);
+                drop(ready);
+
+                (sender_rd, (receiver_wr, end)) = tokio::select! {
+                    wr_chan = &mut sender_handle => {
+                        ct.cancel();
+                        let rd_chan = receiver_handle.await;
+                        (wr_chan.expect("no panic in write task"), rd_chan.expect("no error in read task"))
+                    },
+                    rd_chan = &mut receiver_handle => {
+                        ct.cancel();
+                        let wr_chan = sender_handle.await;
+                        (wr_chan.expect("no panic in write task"), rd_chan.expect("no error in read task"))
+                    }
+                };
+
+                connected.send_replace(false);
+            }
+            Err(_) => {
+                // The connection ended before ServerSync.
                 ct.cancel();
-                let wr_chan = sender_handle.await;
-                (wr_chan.expect("no panic in write task"), rd_chan.expect("no error in read task"))
+                (receiver_wr, end) = receiver_handle.await.expect("no error in read task");
             }
-        };
+        }
+
+        match end.rejection {
+            Some(rejected) if rejected.is_fatal() => {
+                error!("{}. Not reconnecting.", rejected);
+                break;
+            }
+            Some(rejected) if rejected.kind == RejectType::UsernameInUse => {
+                // Past the last suffix, start over with the name as configured.
+                username_attempt = (username_attempt + 1) % (MAX_USERNAME_SUFFIX + 1);
+                username = if username_attempt == 0 {
+                    cfg.username.clone()
+                } else {
+                    format!("{}_{}", cfg.username, username_attempt)
+                };
+                warn!("{}, retrying as {:?}", rejected, username);
+                drop_messages_until(&mut sender_rd, backoff.wait()).await
+            }
+            Some(rejected) => {
+                warn!("{}", rejected);
+                drop_messages_until(&mut sender_rd, backoff.wait()).await
+            }
+            None => {
+                warn!("Disconnected from server.");
+
+                if end.synced {
+                    backoff.reset();
+                    username = cfg.username.clone();
+                    username_attempt = 0;
+                }
+
+                drop_messages_until(&mut sender_rd, backoff.wait()).await
+            }
+        }
     }
 }
 
-pub async fn init(cfg: Config) -> anyhow::Result<(MumbleMsgSink, MumbleMsgSource)> {
+/**
+ * Connect to the server in the background, reconnecting whenever the connection drops.
+ * Our voice is sent to the voice target id stored in `voice_target`, which is
+ * reset to normal talking on every reconnect.
+ *
+ * Besides the message channels, returns a watch channel that tells whether
+ * we are currently connected, which we count from ServerSync on.
+ */
+pub async fn init(
+    cfg: Config,
+    voice_target: Arc<AtomicU8>,
+) -> anyhow::Result<(MumbleMsgSink, MumbleMsgSource, watch::Receiver<bool>)> {
     info!("Connecting...");
 
     let (sender_wr, sender_rd) = mpsc::channel(16);
     let (receiver_wr, receiver_rd) = mpsc::channel(16);
+    let (connected_wr, connected_rd) = watch::channel(false);
 
     let sender_wr2 = sender_wr.clone();
 
-    tokio::spawn(reconnect_task(cfg, sender_rd, sender_wr2, receiver_wr));
+    tokio::spawn(reconnect_task(
+        cfg,
+        sender_rd,
+        sender_wr2,
+        receiver_wr,
+        connected_wr,
+        voice_target,
+    ));
+
+    Ok((sender_wr, receiver_rd, connected_rd))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn frame(tag: u16, payload: &[u8]) -> Vec<u8> {
+        let mut data = tag.to_be_bytes().to_vec();
+        data.extend((payload.len() as u32).to_be_bytes());
+        data.extend(payload);
+        data
+    }
+
+    /**
+     * Read messages until the input runs out or breaks the framing.
+     * Panicking (or hanging) is the only failure.
+     */
+    async fn read_all(mut input: &[u8]) -> Vec<Option<MumbleMsg>> {
+        let mut msgs = vec![];
+        while let Ok(msg) = read_message(&mut input).await {
+            msgs.push(msg);
+        }
+        msgs
+    }
+
+    /// Hand-picked inputs around the edges of the framing and protobuf decoding.
+    fn corpus() -> Vec<Vec<u8>> {
+        let ping = mumble_proto::Ping {
+            timestamp: Some(1),
+            ..Default::default()
+        }
+        .encode_to_vec();
+
+        vec![
+            vec![],
+            vec![0x00],
+            vec![0x00, 0x03],
+            vec![0x00, 0x03, 0x00, 0x00],
+            vec![0x00, 0x03, 0xFF, 0xFF, 0xFF, 0xFF],
+            vec![0x00, 0x03, 0x00, 0x00, 0x00, 0x10, 0x08],
+            frame(MumbleType::Ping as u16, &[]),
+            frame(MumbleType::Ping as u16, &ping),
+            frame(MumbleType::Ping as u16, &ping[..ping.len() - 1]),
+            frame(MumbleType::Ping as u16, &[0xFF; 16]),
+            frame(MumbleType::TextMessage as u16, &[0x22, 0x02, 0xC3, 0x28]),
+            frame(MumbleType::UserState as u16, &[0x08, 0xFF, 0xFF, 0xFF]),
+            frame(MumbleType::ChannelState as u16, &[0x0A, 0x7F]),
+            frame(MumbleType::UDPTunnel as u16, &[]),
+            frame(MumbleType::UDPTunnel as u16, &[0xF8; 64]),
+            frame(MumbleType::PluginDataTransmission as u16, &[0x08, 0x01]),
+            frame(27, b"from the future"),
+            frame(u16::MAX, &[0; 32]),
+            [
+                frame(u16::MAX, b"skip"),
+                frame(MumbleType::Ping as u16, &ping),
+            ]
+            .concat(),
+        ]
+    }
+
+    #[test]
+    fn backoff_grows_from_a_zero_minimum() {
+        let mut backoff = Backoff::new(Duration::ZERO, Duration::from_secs(1));
+
+        assert_eq!(backoff.next_delay(), Duration::ZERO);
+        for _ in 0..3 {
+            assert!(backoff.next_delay() >= MIN_BACKOFF_STEP);
+        }
+        for _ in 0..10 {
+            backoff.next_delay();
+        }
+        assert_eq!(backoff.current, Duration::from_secs(1));
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn corpus_does_not_panic() {
+        for input in corpus() {
+            read_all(&input).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn random_frames_do_not_panic() {
+        let mut rng = StdRng::seed_from_u64(0x6d75_6d62_6c65);
+
+        for _ in 0..10_000 {
+            let tag = rng.random_range(0..32u16);
+            let len = rng.random_range(0..64usize);
+            let payload: Vec<u8> = (0..len).map(|_| rng.random()).collect();
+
+            let mut input = frame(tag, &payload);
+
+            // Sometimes cut the frame short or leave junk after it.
+            match rng.random_range(0..4) {
+                0 => input.truncate(rng.random_range(0..input.len())),
+                1 => input.extend((0..rng.random_range(1..8)).map(|_| rng.random::<u8>())),
+                _ => {}
+            }
+
+            read_all(&input).await;
+        }
+    }
+
+    #[test]
+    fn random_payloads_of_every_type_do_not_panic() {
+        let mut rng = StdRng::seed_from_u64(42);
+
+        for tag in 0..=MumbleType::PluginDataTransmission as u16 {
+            let tag = MumbleType::from_u16(tag).unwrap();
+
+            for _ in 0..500 {
+                let len = rng.random_range(0..128usize);
+                let payload: Vec<u8> = (0..len).map(|_| rng.random()).collect();
+                let _ = MumbleMsg::from_tagged_data(tag, &payload);
+            }
+        }
+    }
 
-    Ok((sender_wr, receiver_rd))
+    #[tokio::test]
+    async fn unknown_types_are_skipped() {
+        let ping = mumble_proto::Ping {
+            timestamp: Some(1234),
+            ..Default::default()
+        };
+        let input = [
+            frame(u16::MAX, b"unknown"),
+            frame(MumbleType::Ping as u16, &ping.encode_to_vec()),
+        ]
+        .concat();
+
+        let msgs = read_all(&input).await;
+
+        assert_eq!(msgs.len(), 2);
+        assert!(msgs[0].is_none());
+        assert!(matches!(&msgs[1], Some(MumbleMsg::Ping(p)) if p.timestamp == Some(1234)));
+    }
+
+    #[tokio::test]
+    async fn malformed_messages_are_skipped() {
+        let input = [
+            frame(MumbleType::Ping as u16, &[0xFF; 8]),
+            frame(MumbleType::Ping as u16, &[]),
+        ]
+        .concat();
+
+        let msgs = read_all(&input).await;
+
+        assert_eq!(msgs.len(), 2);
+        assert!(msgs[0].is_none());
+        assert!(matches!(msgs[1], Some(MumbleMsg::Ping(_))));
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_are_rejected() {
+        // Only the header: the oversized body must not even be waited for.
+        let input = [
+            (MumbleType::UDPTunnel as u16).to_be_bytes().as_slice(),
+            &(MAX_FRAME_SIZE + 1).to_be_bytes(),
+        ]
+        .concat();
+
+        assert!(read_message(&mut input.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn messages_round_trip() {
+        let msgs = [
+            MumbleMsg::Version(mumble_proto::Version {
+                release: Some("MumbleBot".into()),
+                ..Default::default()
+            }),
+            MumbleMsg::UDPTunnel(vec![0x80, 0x01, 0x02]),
+            MumbleMsg::TextMessage(mumble_proto::TextMessage {
+                message: "héllo".into(),
+                channel_id: vec![1, 2],
+                ..Default::default()
+            }),
+            MumbleMsg::PluginDataTransmission(mumble_proto::PluginDataTransmission {
+                data: Some(vec![1, 2, 3]),
+                ..Default::default()
+            }),
+        ];
+
+        let mut wire = vec![];
+        for msg in &msgs {
+            try_send_msg(&mut wire, msg).await.unwrap();
+        }
+
+        let read = read_all(&wire).await;
+
+        assert_eq!(read.len(), msgs.len());
+        for (sent, received) in msgs.iter().zip(read) {
+            let received = received.unwrap();
+            assert_eq!(sent.tag(), received.tag());
+            assert_eq!(sent.as_data(), received.as_data());
+        }
+    }
+
+    #[tokio::test]
+    async fn msg_golden_fixtures() {
+        let fixtures: &[(MumbleMsg, &[u8])] = &[
+            (
+                MumbleMsg::Ping(mumble_proto::Ping {
+                    timestamp: Some(1),
+                    ..Default::default()
+                }),
+                &[0x00, 0x03, 0x00, 0x00, 0x00, 0x02, 0x08, 0x01],
+            ),
+            (
+                MumbleMsg::Version(mumble_proto::Version {
+                    version_v1: Some(0x00010500),
+                    release: Some("MumbleBot".into()),
+                    ..Default::default()
+                }),
+                &[
+                    0x00, 0x00, 0x00, 0x00, 0x00, 0x0F, 0x08, 0x80, 0x8A, 0x04, 0x12, 0x09, b'M',
+                    b'u', b'm', b'b', b'l', b'e', b'B', b'o', b't',
+                ],
+            ),
+            (
+                MumbleMsg::TextMessage(mumble_proto::TextMessage {
+                    channel_id: vec![1],
+                    message: "hi".into(),
+                    ..Default::default()
+                }),
+                &[
+                    0x00, 0x0B, 0x00, 0x00, 0x00, 0x06, 0x18, 0x01, 0x2A, 0x02, b'h', b'i',
+                ],
+            ),
+        ];
+
+        for (msg, expected) in fixtures {
+            let mut wire = vec![];
+            try_send_msg(&mut wire, msg).await.unwrap();
+            assert_eq!(&wire, expected, "encoding {:?}", msg);
+        }
+    }
+
+    /// Packet format, voice target, sequence number, Opus data and the expected bytes on the wire.
+    type VoiceFixture = (VoiceFormat, u8, u64, &'static [u8], &'static [u8]);
+
+    #[tokio::test]
+    async fn voice_golden_fixtures() {
+        let fixtures: &[VoiceFixture] = &[
+            (
+                VoiceFormat::Legacy,
+                0,
+                5,
+                &[0xAA, 0xBB],
+                &[
+                    0x00, 0x01, 0x00, 0x00, 0x00, 0x05, 0x80, 0x05, 0x02, 0xAA, 0xBB,
+                ],
+            ),
+            (
+                VoiceFormat::Legacy,
+                1,
+                300,
+                &[0xCC],
+                &[
+                    0x00, 0x01, 0x00, 0x00, 0x00, 0x05, 0x81, 0x81, 0x2C, 0x01, 0xCC,
+                ],
+            ),
+            (
+                VoiceFormat::Protobuf,
+                0,
+                5,
+                &[0xAA, 0xBB],
+                &[
+                    0x00, 0x01, 0x00, 0x00, 0x00, 0x09, 0x00, 0x08, 0x00, 0x20, 0x05, 0x2A, 0x02,
+                    0xAA, 0xBB,
+                ],
+            ),
+        ];
+
+        for &(format, target, seq_nr, opus, expected) in fixtures {
+            let packet = voice::encode_voice_packet(format, target, seq_nr, opus, false);
+
+            let mut wire = vec![];
+            try_send_voice_data(&mut wire, &packet).await.unwrap();
+            assert_eq!(wire, expected, "encoding {:?} seq {}", format, seq_nr);
+        }
+    }
 }
@@ -0,0 +1,382 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+use tokio::sync::mpsc;
+
+use crate::{
+    mumble_proto,
+    state::ServerLimits,
+    text,
+    types::{MumbleMsg, Request, Song},
+};
+
+/// Queue entries shown below the current song.
+const NEXT_ENTRIES: usize = 5;
+
+/// Least time between two comment updates, to stay clear of the server's message rate limit.
+const MIN_UPDATE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the elapsed time is refreshed while nothing else changes.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/**
+ * The song that is playing, as shown in the panel.
+ */
+pub struct Current<'a> {
+    pub song: &'a Song,
+    pub request: &'a Request,
+    pub position: Duration,
+    pub paused: bool,
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+/**
+ * `name` cut down to `max_chars` characters, ending in an ellipsis if anything
+ * was cut.
+ */
+fn shorten(name: &str, max_chars: Option<usize>) -> String {
+    match max_chars {
+        Some(max_chars) if name.chars().count() > max_chars => {
+            let mut short: String = name.chars().take(max_chars).collect();
+            short.push('…');
+            short
+        }
+        _ => name.to_string(),
+    }
+}
+
+fn render_with(
+    current: Option<&Current>,
+    queue: &VecDeque<(Song, Request)>,
+    entries: usize,
+    name_chars: Option<usize>,
+) -> String {
+    let mut html = String::new();
+
+    match current {
+        Some(current) => {
+            html.push_str("<b>Now playing</b><br>");
+            html.push_str(&format!(
+                "<i>{}</i><br>",
+                text::escape_html(&shorten(&current.song.name, name_chars))
+            ));
+
+            if let Some(requester) = &current.request.requester {
+                html.push_str(&format!(
+                    "requested by {}<br>",
+                    text::escape_html(&shorten(requester, name_chars))
+                ));
+            }
+
+            html.push_str(&format_duration(current.position));
+            if let Some(duration) = current.song.duration {
+                html.push_str(&format!(" / {}", format_duration(duration)));
+            }
+            if current.paused {
+                html.push_str(" (paused)");
+            }
+        }
+        None => html.push_str("<b>Nothing playing</b>"),
+    }
+
+    if !queue.is_empty() && entries > 0 {
+        html.push_str("<br><br><b>Up next</b>");
+        for (i, (song, _)) in queue.iter().take(entries).enumerate() {
+            html.push_str(&format!("<br>{}. {}", i + 1, text::escape_html(&song.name)));
+        }
+        if queue.len() > entries {
+            html.push_str(&format!("<br>and {} more", queue.len() - entries));
+        }
+    }
+
+    html
+}
+
+/**
+ * The panel for our user comment: the current song with its requester and
+ * progress, and the first few songs in the queue. Queue entries are left out
+ * as needed to stay within the server's message length, then the song name
+ * and requester are shortened. Should even a bare heading be too long, the
+ * comment is left empty.
+ */
+pub fn render(
+    current: Option<&Current>,
+    queue: &VecDeque<(Song, Request)>,
+    limits: &ServerLimits,
+) -> String {
+    let finish = |html: String| {
+        if limits.allow_html {
+            html
+        } else {
+            text::html_to_plain(&html)
+        }
+    };
+    let fits = |comment: &str| {
+        limits
+            .message_length
+            .is_none_or(|max_len| text::message_length(comment) <= max_len as usize)
+    };
+
+    for entries in (0..=NEXT_ENTRIES).rev() {
+        let comment = finish(render_with(current, queue, entries, None));
+        if fits(&comment) {
+            return comment;
+        }
+    }
+
+    let longest_name = current.map_or(0, |current| {
+        let requester = current.request.requester.as_deref().unwrap_or_default();
+        current
+            .song
+            .name
+            .chars()
+            .count()
+            .max(requester.chars().count())
+    });
+    for name_chars in (0..longest_name).rev() {
+        let comment = finish(render_with(current, queue, 0, Some(name_chars)));
+        if fits(&comment) {
+            return comment;
+        }
+    }
+
+    let heading = match current {
+        Some(_) => "<b>Now playing</b>",
+        None => "<b>Nothing playing</b>",
+    };
+    let comment = finish(heading.to_string());
+    if fits(&comment) {
+        comment
+    } else {
+        String::new()
+    }
+}
+
+/**
+ * Keeps track of when our comment needs updating, and sends the updates.
+ */
+#[derive(Default)]
+pub struct CommentUpdater {
+    last_update: Option<Instant>,
+    last_comment: Option<String>,
+    dirty: bool,
+}
+
+impl CommentUpdater {
+    /**
+     * Something shown in the panel changed.
+     */
+    pub fn changed(&mut self) {
+        self.dirty = true;
+    }
+
+    /**
+     * Send the panel again even if it looks the same, e.g. after reconnecting.
+     */
+    pub fn invalidate(&mut self) {
+        self.last_comment = None;
+        self.dirty = true;
+    }
+
+    /**
+     * When the comment should next be updated, if at all.
+     */
+    pub fn next_update(&self, playing: bool) -> Option<Instant> {
+        let interval = if self.dirty {
+            MIN_UPDATE_INTERVAL
+        } else if playing {
+            REFRESH_INTERVAL
+        } else {
+            return None;
+        };
+
+        Some(
+            self.last_update
+                .map_or_else(Instant::now, |last| last + interval),
+        )
+    }
+
+    pub async fn update(
+        &mut self,
+        msg_sender: &mpsc::Sender<MumbleMsg>,
+        comment: String,
+    ) -> anyhow::Result<()> {
+        self.dirty = false;
+        self.last_update = Some(Instant::now());
+
+        if self.last_comment.as_ref() == Some(&comment) {
+            return Ok(());
+        }
+
+        debug!("Updating now playing comment.");
+
+        // Without a session, the server applies the change to ourselves.
+        let msg = mumble_proto::UserState {
+            comment: Some(comment.clone()),
+            ..Default::default()
+        };
+        msg_sender.send(MumbleMsg::UserState(msg)).await?;
+
+        self.last_comment = Some(comment);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{SongType, TextTarget};
+
+    fn song(name: &str) -> Song {
+        Song {
+            name: name.to_string(),
+            id: name.to_string(),
+            song_type: SongType::YouTube,
+            duration: Some(Duration::from_secs(200)),
+            image_url: None,
+        }
+    }
+
+    fn request(requester: &str) -> Request {
+        Request {
+            reply_to: TextTarget::Channel(0),
+            requester: Some(requester.to_string()),
+        }
+    }
+
+    fn queue(len: usize) -> VecDeque<(Song, Request)> {
+        (1..=len)
+            .map(|i| (song(&format!("Song {}", i)), request("alice")))
+            .collect()
+    }
+
+    fn limited(message_length: u32) -> ServerLimits {
+        ServerLimits {
+            message_length: Some(message_length),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn only_the_first_entries_of_the_queue_are_shown() {
+        let song = song("Playing");
+        let request = request("bob");
+        let current = Current {
+            song: &song,
+            request: &request,
+            position: Duration::from_secs(65),
+            paused: false,
+        };
+
+        let panel = render(Some(&current), &queue(8), &ServerLimits::default());
+        assert!(panel.contains("<i>Playing</i>"), "{}", panel);
+        assert!(panel.contains("requested by bob"), "{}", panel);
+        assert!(panel.contains("1:05 / 3:20"), "{}", panel);
+        assert!(panel.contains("5. Song 5"), "{}", panel);
+        assert!(!panel.contains("6. Song 6"), "{}", panel);
+        assert!(panel.ends_with("and 3 more"), "{}", panel);
+
+        let panel = render(None, &queue(2), &ServerLimits::default());
+        assert!(panel.starts_with("<b>Nothing playing</b>"), "{}", panel);
+        assert!(panel.ends_with("2. Song 2"), "{}", panel);
+    }
+
+    #[test]
+    fn panels_are_plain_text_without_html() {
+        let limits = ServerLimits {
+            allow_html: false,
+            ..Default::default()
+        };
+        let song = song("Tom & Jerry");
+        let request = request("<bob>");
+        let current = Current {
+            song: &song,
+            request: &request,
+            position: Duration::ZERO,
+            paused: true,
+        };
+
+        let panel = render(Some(&current), &queue(1), &limits);
+        assert_eq!(
+            panel,
+            "Now playing\nTom & Jerry\nrequested by <bob>\n0:00 / 3:20 (paused)\nUp next\n1. Song 1"
+        );
+    }
+
+    #[test]
+    fn entries_are_dropped_to_fit() {
+        let queue = queue(8);
+        let max_len = text::message_length(&render_with(None, &queue, 2, None));
+
+        let panel = render(None, &queue, &limited(max_len as u32));
+        assert!(text::message_length(&panel) <= max_len);
+        assert!(panel.contains("2. Song 2"), "{}", panel);
+        assert!(!panel.contains("3. Song 3"), "{}", panel);
+        assert!(panel.ends_with("and 6 more"), "{}", panel);
+    }
+
+    #[test]
+    fn long_names_are_shortened_to_fit() {
+        let song = song(&"Very long title ".repeat(40));
+        let request = request(&"Someone ".repeat(40));
+        let current = Current {
+            song: &song,
+            request: &request,
+            position: Duration::ZERO,
+            paused: false,
+        };
+
+        for max_len in [200, 100, 80] {
+            let panel = render(Some(&current), &queue(3), &limited(max_len));
+            assert!(
+                text::message_length(&panel) <= max_len as usize,
+                "{}",
+                panel
+            );
+            assert!(panel.starts_with("<b>Now playing</b>"), "{}", panel);
+            assert!(panel.contains("…</i>"), "{}", panel);
+            assert!(!panel.contains("Up next"), "{}", panel);
+        }
+
+        for max_len in [60, 20] {
+            assert_eq!(
+                render(Some(&current), &queue(3), &limited(max_len)),
+                "<b>Now playing</b>"
+            );
+        }
+        assert_eq!(render(Some(&current), &queue(3), &limited(10)), "");
+    }
+
+    #[test]
+    fn updates_wait_for_changes_and_refreshes() {
+        let mut updater = CommentUpdater::default();
+        assert_eq!(updater.next_update(false), None);
+
+        let before = Instant::now();
+        let next = updater.next_update(true).unwrap();
+        assert!(next >= before && next <= Instant::now());
+
+        let last = Instant::now();
+        updater.last_update = Some(last);
+        assert_eq!(updater.next_update(false), None);
+        assert_eq!(updater.next_update(true), Some(last + REFRESH_INTERVAL));
+
+        updater.changed();
+        assert_eq!(updater.next_update(false), Some(last + MIN_UPDATE_INTERVAL));
+        assert_eq!(updater.next_update(true), Some(last + MIN_UPDATE_INTERVAL));
+        assert_eq!(MIN_UPDATE_INTERVAL, Duration::from_secs(5));
+        assert_eq!(REFRESH_INTERVAL, Duration::from_secs(30));
+    }
+}
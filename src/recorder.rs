@@ -0,0 +1,580 @@
+/*!
+ * Recording the voice in our channel: one Ogg/Opus file per speaker, all
+ * aligned to the start of the recording, and a mixdown of everyone together.
+ */
+
+use std::{
+    collections::{btree_map::Entry, BTreeMap, VecDeque},
+    fs::File,
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, info, warn};
+use ogg::{PacketWriteEndInfo, PacketWriter};
+use opus::{Application, Channels, Encoder};
+use serde::Serialize;
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+};
+
+use crate::voice::VoiceFrame;
+
+const SAMPLE_RATE: u32 = 48_000;
+const CHANNELS: usize = 2;
+
+/// Samples per channel in each Opus packet we write (20 ms).
+const PACKET_SAMPLES: usize = SAMPLE_RATE as usize / 50;
+
+/// Gaps shorter than this are not filled with silence, so a speaker's own timing wins over jitter.
+const MIN_GAP: u64 = SAMPLE_RATE as u64 / 20;
+
+/// Voice may arrive this late and still make it into the mixdown.
+const MIX_DELAY: u64 = SAMPLE_RATE as u64 / 2;
+
+/// Frames waiting to be written; more than this and they are dropped.
+const QUEUE_LENGTH: usize = 512;
+
+const DEFAULT_RECORDING_DIR: &str = "recordings";
+
+/**
+ * An Ogg/Opus file that interleaved stereo PCM is encoded into.
+ */
+struct OggOpusWriter {
+    writer: PacketWriter<'static, BufWriter<File>>,
+    encoder: Encoder,
+    serial: u32,
+    /// PCM not yet making up a full packet.
+    pending: Vec<i16>,
+    /// Samples per channel encoded so far.
+    encoded: u64,
+}
+
+impl OggOpusWriter {
+    fn create(path: &Path) -> anyhow::Result<Self> {
+        let mut encoder = Encoder::new(SAMPLE_RATE, Channels::Stereo, Application::Voip)?;
+        let pre_skip = encoder.get_lookahead()? as u16;
+
+        let serial = rand::random();
+        let mut writer = PacketWriter::new(BufWriter::new(File::create(path)?));
+
+        let mut head = b"OpusHead".to_vec();
+        head.push(1); // version
+        head.push(CHANNELS as u8);
+        head.extend(pre_skip.to_le_bytes());
+        head.extend(SAMPLE_RATE.to_le_bytes());
+        head.extend(0i16.to_le_bytes()); // output gain
+        head.push(0); // channel mapping family
+        writer.write_packet(head, serial, PacketWriteEndInfo::EndPage, 0)?;
+
+        let vendor = b"mumblebot";
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend((vendor.len() as u32).to_le_bytes());
+        tags.extend(vendor);
+        tags.extend(0u32.to_le_bytes()); // no comments
+        writer.write_packet(tags, serial, PacketWriteEndInfo::EndPage, 0)?;
+
+        Ok(OggOpusWriter {
+            writer,
+            encoder,
+            serial,
+            pending: Vec::with_capacity(PACKET_SAMPLES * CHANNELS),
+            encoded: 0,
+        })
+    }
+
+    /**
+     * Samples per channel written so far, including those not yet encoded.
+     */
+    fn position(&self) -> u64 {
+        self.encoded + (self.pending.len() / CHANNELS) as u64
+    }
+
+    fn write(&mut self, pcm: &[i16]) -> anyhow::Result<()> {
+        for chunk in pcm.chunks(PACKET_SAMPLES * CHANNELS) {
+            let room = PACKET_SAMPLES * CHANNELS - self.pending.len();
+            let (now, later) = chunk.split_at(room.min(chunk.len()));
+
+            self.pending.extend_from_slice(now);
+            if self.pending.len() == PACKET_SAMPLES * CHANNELS {
+                self.encode_pending(PacketWriteEndInfo::NormalPacket)?;
+            }
+            self.pending.extend_from_slice(later);
+        }
+
+        Ok(())
+    }
+
+    fn write_silence(&mut self, samples: u64) -> anyhow::Result<()> {
+        let silence = vec![0; PACKET_SAMPLES * CHANNELS];
+        let mut left = samples as usize * CHANNELS;
+
+        while left > 0 {
+            let len = left.min(silence.len());
+            self.write(&silence[..len])?;
+            left -= len;
+        }
+
+        Ok(())
+    }
+
+    fn encode_pending(&mut self, end: PacketWriteEndInfo) -> anyhow::Result<()> {
+        self.pending.resize(PACKET_SAMPLES * CHANNELS, 0);
+
+        let mut packet = vec![0; 4000];
+        let len = self.encoder.encode(&self.pending, &mut packet)?;
+        packet.truncate(len);
+
+        self.encoded += PACKET_SAMPLES as u64;
+        self.pending.clear();
+
+        // The granule position counts decoded samples, the skipped ones included.
+        self.writer
+            .write_packet(packet, self.serial, end, self.encoded)?;
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> anyhow::Result<()> {
+        self.encode_pending(PacketWriteEndInfo::EndStream)?;
+        std::io::Write::flush(self.writer.inner_mut())?;
+
+        Ok(())
+    }
+}
+
+/**
+ * One speaker's file.
+ */
+struct Track {
+    name: String,
+    file: String,
+    writer: OggOpusWriter,
+}
+
+#[derive(Serialize)]
+struct Participant {
+    name: String,
+    session: u32,
+    file: String,
+}
+
+/**
+ * What is written to `manifest.json` next to the recorded files.
+ */
+#[derive(Serialize)]
+struct Manifest {
+    channel: String,
+    started: String,
+    stopped: String,
+    participants: Vec<Participant>,
+    mixdown: String,
+}
+
+/**
+ * A decoded frame of someone's voice, as it is handed to the writer.
+ */
+struct RecordedFrame {
+    session: u32,
+    name: String,
+    pcm: Arc<[i16]>,
+    /// When the frame was heard, which decides where it goes in the recording.
+    heard: Instant,
+}
+
+/**
+ * Writes the recording files on a blocking thread, as the frames come in.
+ */
+struct Writer {
+    dir: PathBuf,
+    started: Instant,
+    tracks: BTreeMap<u32, Track>,
+    mixdown: OggOpusWriter,
+    /// Mixed samples from `mixdown.position()` on, still open to late voice.
+    mix: VecDeque<i32>,
+}
+
+impl Writer {
+    fn add(&mut self, frame: RecordedFrame) -> anyhow::Result<()> {
+        let len = (frame.pcm.len() / CHANNELS) as u64;
+        let heard = frame.heard.saturating_duration_since(self.started);
+        let end = heard.as_secs_f64() * SAMPLE_RATE as f64;
+        let start = (end as u64).saturating_sub(len);
+
+        let track = match self.tracks.entry(frame.session) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let file = format!("{}-{}.opus", frame.session, file_name(&frame.name));
+                info!("Recording {} to {}", frame.name, file);
+
+                entry.insert(Track {
+                    name: frame.name.clone(),
+                    writer: OggOpusWriter::create(&self.dir.join(&file))?,
+                    file,
+                })
+            }
+        };
+
+        let position = track.writer.position();
+        if start > position + MIN_GAP {
+            track.writer.write_silence(start - position)?;
+        }
+
+        let position = track.writer.position();
+        track.writer.write(&frame.pcm)?;
+
+        // Flush first, so that after a pause the mix only holds the open window.
+        self.flush_mix(end as u64)?;
+        self.mix_in(position, &frame.pcm);
+
+        Ok(())
+    }
+
+    fn mix_in(&mut self, position: u64, pcm: &[i16]) {
+        let mix_start = self.mixdown.position();
+        let Some(offset) = position.checked_sub(mix_start) else {
+            debug!("Voice arrived too late for the mixdown.");
+            return;
+        };
+
+        let offset = offset as usize * CHANNELS;
+        if self.mix.len() < offset + pcm.len() {
+            self.mix.resize(offset + pcm.len(), 0);
+        }
+
+        for (mixed, &sample) in self.mix.iter_mut().skip(offset).zip(pcm) {
+            *mixed += sample as i32;
+        }
+    }
+
+    /**
+     * Write out the part of the mix that is old enough to be complete.
+     */
+    fn flush_mix(&mut self, now: u64) -> anyhow::Result<()> {
+        let complete = now.saturating_sub(MIX_DELAY);
+        let mix_start = self.mixdown.position();
+        if complete <= mix_start {
+            return Ok(());
+        }
+
+        let samples = (complete - mix_start) as usize * CHANNELS;
+        let mixed: Vec<i16> = self
+            .mix
+            .drain(..samples.min(self.mix.len()))
+            .map(|sample| sample.clamp(i16::MIN as i32, i16::MAX as i32) as i16)
+            .collect();
+        self.mixdown.write(&mixed)?;
+
+        // Nobody spoke for the rest, which is written without holding it in memory.
+        self.mixdown
+            .write_silence(((samples - mixed.len()) / CHANNELS) as u64)
+    }
+
+    fn finish(mut self, channel: String, started: SystemTime) -> anyhow::Result<PathBuf> {
+        let end = self.started.elapsed().as_secs_f64() * SAMPLE_RATE as f64;
+        self.flush_mix(end as u64 + MIX_DELAY)?;
+        self.mixdown.finish()?;
+
+        let mut participants = vec![];
+        for (session, track) in self.tracks {
+            track.writer.finish()?;
+            participants.push(Participant {
+                name: track.name,
+                session,
+                file: track.file,
+            });
+        }
+
+        let manifest = Manifest {
+            channel,
+            started: humantime::format_rfc3339_seconds(started).to_string(),
+            stopped: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            participants,
+            mixdown: "mixdown.opus".into(),
+        };
+
+        let path = self.dir.join("manifest.json");
+        serde_json::to_writer_pretty(File::create(&path)?, &manifest)?;
+
+        Ok(self.dir)
+    }
+}
+
+/**
+ * Turn a user name into something safe to use in a file name.
+ */
+fn file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/**
+ * Create a new directory `name` under `dir`, adding a suffix if a recording
+ * started in the same second already took that name.
+ */
+fn create_recording_dir(dir: &Path, name: &str) -> io::Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+
+    let mut path = dir.join(name);
+    let mut suffix = 1;
+    loop {
+        match std::fs::create_dir(&path) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                path = dir.join(format!("{}-{}", name, suffix));
+                suffix += 1;
+            }
+            result => return result.map(|()| path),
+        }
+    }
+}
+
+/**
+ * A recording in progress.
+ */
+pub struct Recording {
+    frames: mpsc::Sender<RecordedFrame>,
+    task: JoinHandle<anyhow::Result<PathBuf>>,
+}
+
+impl Recording {
+    /**
+     * Start recording into a new directory under `dir` (or `recordings`),
+     * named after the current time.
+     */
+    pub fn start(dir: Option<&str>, channel: String) -> anyhow::Result<Self> {
+        let started_at = SystemTime::now();
+        let started = Instant::now();
+
+        let name = started_at.duration_since(UNIX_EPOCH)?.as_secs().to_string();
+        let dir = create_recording_dir(Path::new(dir.unwrap_or(DEFAULT_RECORDING_DIR)), &name)?;
+
+        let mut writer = Writer {
+            mixdown: OggOpusWriter::create(&dir.join("mixdown.opus"))?,
+            dir,
+            started,
+            tracks: BTreeMap::new(),
+            mix: VecDeque::new(),
+        };
+
+        info!("Recording {} into {}", channel, writer.dir.display());
+
+        let (frames, mut frames_rx) = mpsc::channel::<RecordedFrame>(QUEUE_LENGTH);
+
+        let task = tokio::task::spawn_blocking(move || {
+            while let Some(frame) = frames_rx.blocking_recv() {
+                writer.add(frame)?;
+            }
+
+            writer.finish(channel, started_at)
+        });
+
+        Ok(Recording { frames, task })
+    }
+
+    /**
+     * Add a frame of voice from a user in the recorded channel. Returns false
+     * once the writer has stopped, which `stop` then reports on.
+     */
+    pub fn heard(&self, frame: &VoiceFrame, name: &str) -> bool {
+        let frame = RecordedFrame {
+            session: frame.session,
+            name: name.into(),
+            pcm: frame.pcm.clone(),
+            heard: Instant::now(),
+        };
+
+        match self.frames.try_send(frame) {
+            Err(TrySendError::Full(_)) => {
+                warn!("Recording can't keep up, dropping voice.");
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+            Ok(()) => true,
+        }
+    }
+
+    /**
+     * Finish the files and write the manifest, returning the directory they are in.
+     */
+    pub async fn stop(self) -> anyhow::Result<PathBuf> {
+        drop(self.frames);
+        self.task.await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        mock_server::{start_bot_with_commands, MockServer, BOT_SESSION, CHANNELS, USERS},
+        mumble_proto,
+        types::MumbleMsg,
+    };
+
+    const FRAME: usize = PACKET_SAMPLES * super::CHANNELS;
+
+    fn writer(dir: &Path) -> Writer {
+        Writer {
+            mixdown: OggOpusWriter::create(&dir.join("mixdown.opus")).unwrap(),
+            dir: dir.to_path_buf(),
+            started: Instant::now(),
+            tracks: BTreeMap::new(),
+            mix: VecDeque::new(),
+        }
+    }
+
+    /**
+     * A 20 ms frame of `sample` from `session`, heard `ms` milliseconds into the recording.
+     */
+    fn frame(writer: &Writer, session: u32, ms: u64, sample: i16) -> RecordedFrame {
+        RecordedFrame {
+            session,
+            name: format!("user {}", session),
+            pcm: vec![sample; FRAME].into(),
+            heard: writer.started + Duration::from_millis(ms),
+        }
+    }
+
+    fn track_position(writer: &Writer, session: u32) -> u64 {
+        writer.tracks[&session].writer.position()
+    }
+
+    #[test]
+    fn tracks_are_aligned_to_the_start_of_the_recording() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = writer(dir.path());
+
+        // Silence up to the first frame, which ends when it was heard.
+        writer.add(frame(&writer, 1, 1000, 100)).unwrap();
+        assert_eq!(track_position(&writer, 1), 48_000);
+
+        // The next frame follows on directly, and so does one that is only a little late.
+        writer.add(frame(&writer, 1, 1020, 100)).unwrap();
+        assert_eq!(track_position(&writer, 1), 48_960);
+        writer.add(frame(&writer, 1, 1050, 100)).unwrap();
+        assert_eq!(track_position(&writer, 1), 49_920);
+
+        // After a pause, the silence is filled in again.
+        writer.add(frame(&writer, 1, 2000, 100)).unwrap();
+        assert_eq!(track_position(&writer, 1), 96_000);
+
+        writer.add(frame(&writer, 2, 500, 100)).unwrap();
+        assert_eq!(track_position(&writer, 2), 24_000);
+
+        let recording = writer.finish("Root".into(), SystemTime::now()).unwrap();
+        let manifest: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(recording.join("manifest.json")).unwrap())
+                .unwrap();
+        assert_eq!(manifest["participants"][0]["file"], "1-user_1.opus");
+        assert_eq!(manifest["participants"][1]["file"], "2-user_2.opus");
+        assert!(recording.join("1-user_1.opus").exists());
+        assert!(recording.join("2-user_2.opus").exists());
+    }
+
+    #[test]
+    fn the_mixdown_waits_for_late_voice() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = writer(dir.path());
+
+        // Everything up to MIX_DELAY before the newest voice is written out.
+        writer.add(frame(&writer, 1, 2000, 100)).unwrap();
+        assert_eq!(writer.mixdown.position(), 96_000 - MIX_DELAY);
+        assert_eq!(writer.mix.len(), MIX_DELAY as usize * super::CHANNELS);
+
+        // Voice heard at the same time is mixed in with it.
+        writer.add(frame(&writer, 2, 2000, 50)).unwrap();
+        let last_frame = writer.mix.len() - FRAME;
+        assert!(writer.mix.range(last_frame..).all(|&sample| sample == 150));
+        assert!(writer.mix.range(..last_frame).all(|&sample| sample == 0));
+
+        // Late voice still within the window is mixed in where it belongs.
+        writer.add(frame(&writer, 3, 1800, 70)).unwrap();
+        let offset =
+            (86_400 - PACKET_SAMPLES - writer.mixdown.position() as usize) * super::CHANNELS;
+        assert!(writer
+            .mix
+            .range(offset..offset + FRAME)
+            .all(|&sample| sample == 70));
+
+        // Voice older than that is only written to its own track.
+        let mix = writer.mix.clone();
+        writer.add(frame(&writer, 4, 1000, 30)).unwrap();
+        assert_eq!(writer.mix, mix);
+        assert_eq!(writer.mixdown.position(), 96_000 - MIX_DELAY);
+        assert_eq!(track_position(&writer, 4), 48_000);
+    }
+
+    #[test]
+    fn recordings_started_in_the_same_second_get_their_own_directory() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let first = create_recording_dir(dir.path(), "1700000000").unwrap();
+        let second = create_recording_dir(dir.path(), "1700000000").unwrap();
+        let third = create_recording_dir(dir.path(), "1700000000").unwrap();
+
+        assert_eq!(first, dir.path().join("1700000000"));
+        assert_eq!(second, dir.path().join("1700000000-1"));
+        assert_eq!(third, dir.path().join("1700000000-2"));
+    }
+
+    #[tokio::test]
+    async fn record_sets_the_flag_and_writes_a_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut server = MockServer::start().await;
+        let mut cfg = server.config();
+        cfg.recording_dir = Some(dir.path().to_string_lossy().into());
+        let _bot = start_bot_with_commands(cfg).await;
+
+        let mut conn = server.accept().await;
+        conn.expect_login().await;
+        conn.sync(BOT_SESSION, CHANNELS, USERS).await;
+
+        for (command, flag) in [(".record start", true), (".record stop", false)] {
+            conn.send(MumbleMsg::TextMessage(mumble_proto::TextMessage {
+                actor: Some(2),
+                session: vec![BOT_SESSION],
+                message: command.into(),
+                ..Default::default()
+            }))
+            .await;
+
+            let recording = conn
+                .expect(|msg| match msg {
+                    MumbleMsg::UserState(state) => state.recording,
+                    _ => None,
+                })
+                .await;
+            assert_eq!(recording, flag);
+
+            let announcement = conn
+                .expect(|msg| match msg {
+                    MumbleMsg::TextMessage(text) => Some(text),
+                    _ => None,
+                })
+                .await;
+            assert!(!announcement.channel_id.is_empty(), "{:?}", announcement);
+        }
+
+        let recordings: Vec<_> = std::fs::read_dir(dir.path()).unwrap().collect();
+        assert_eq!(recordings.len(), 1);
+
+        let recording = recordings[0].as_ref().unwrap().path();
+        let manifest: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(recording.join("manifest.json")).unwrap())
+                .unwrap();
+        assert_eq!(manifest["mixdown"], "mixdown.opus");
+        assert!(recording.join("mixdown.opus").exists());
+    }
+}
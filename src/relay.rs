@@ -0,0 +1,480 @@
+/*!
+ * Relaying between our channel and a channel elsewhere: on another server,
+ * or another channel on ours. Each relay is a connection of its own, which
+ * joins the other channel and passes voice (and optionally chat) both ways.
+ */
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{atomic::AtomicU8, Arc},
+};
+
+use log::{info, warn};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::{
+    mumble_proto, net, sound,
+    state::ServerState,
+    text,
+    types::{Config, MumbleMsg, MumbleMsgSink, MumbleMsgSource, RelayConfig, TextTarget},
+    voice::{self, VoiceFrame},
+};
+
+/// Our main connection's number; relays are numbered from 1.
+pub const MAIN_CONNECTION: usize = 0;
+
+/**
+ * One of our connections: the server it is on, and its session there.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct OwnSession {
+    host: String,
+    port: u16,
+    session: u32,
+}
+
+/**
+ * The sessions of all our connections, so that nothing we send is relayed
+ * back to where it came from.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct OwnSessions {
+    sessions: Arc<std::sync::Mutex<HashMap<usize, OwnSession>>>,
+}
+
+impl OwnSessions {
+    pub fn set(&self, connection: usize, host: &str, port: u16, session: u32) {
+        let own = OwnSession {
+            host: host.into(),
+            port,
+            session,
+        };
+        self.sessions.lock().unwrap().insert(connection, own);
+    }
+
+    /**
+     * Whether `session` on the server at `host`:`port` is one of our connections.
+     */
+    pub fn contains(&self, host: &str, port: u16, session: u32) -> bool {
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .any(|own| own.host == host && own.port == port && own.session == session)
+    }
+}
+
+/**
+ * The name of whoever sent a chat message worth relaying: one to our
+ * channel that is not a command, and not from one of our connections.
+ */
+pub fn chat_sender<'a>(
+    msg: &mumble_proto::TextMessage,
+    server_state: &'a ServerState,
+    own_sessions: &OwnSessions,
+    host: &str,
+    port: u16,
+) -> Option<&'a str> {
+    let actor = msg.actor?;
+    let own_channel = server_state.own_channel()?;
+
+    if msg.message.starts_with('.')
+        || !msg.channel_id.contains(&own_channel)
+        || own_sessions.contains(host, port, actor)
+    {
+        return None;
+    }
+
+    server_state.user(actor).map(|user| user.name.as_str())
+}
+
+fn format_chat(name: &str, message: &str) -> String {
+    format!("<b>{}</b>: {}", text::escape_html(name), message)
+}
+
+/**
+ * Our end of a relay, which takes the voice and chat from our channel.
+ */
+pub struct Relay {
+    /// Voice from our channel, sent out on the relay's connection.
+    outgoing: sound::Mixer,
+    chat: Option<mpsc::Sender<String>>,
+}
+
+impl Relay {
+    /**
+     * Pass on voice heard in our channel.
+     */
+    pub fn heard(&self, frame: &VoiceFrame) {
+        self.outgoing
+            .push(MAIN_CONNECTION, frame.session, &frame.pcm);
+    }
+
+    /**
+     * Pass on a chat message sent to our channel, if chat is relayed.
+     */
+    pub async fn chat(&self, name: &str, message: &str) {
+        if let Some(chat) = &self.chat {
+            let _ = chat.send(format_chat(name, message)).await;
+        }
+    }
+}
+
+/**
+ * The configuration for the connection of the relay numbered `connection`,
+ * which takes what it doesn't set itself from ours.
+ */
+fn relay_config(connection: usize, cfg: &Config, relay: &RelayConfig) -> Config {
+    let mut relay_cfg = cfg.clone();
+    relay_cfg.host = relay.host.clone().unwrap_or_else(|| cfg.host.clone());
+    relay_cfg.port = relay.port.unwrap_or(cfg.port);
+    relay_cfg.username = relay.username.clone();
+    relay_cfg.channel = Some(relay.channel.clone());
+    relay_cfg.register_self = None;
+    relay_cfg.relays = None;
+
+    // Our password is only any good on our own server.
+    let on_our_server = relay_cfg.host == cfg.host && relay_cfg.port == cfg.port;
+    relay_cfg.password = match &relay.password {
+        Some(password) => Some(password.clone()),
+        None if on_our_server => cfg.password.clone(),
+        None => None,
+    };
+
+    if relay.cert_file.is_some() {
+        relay_cfg.cert_file = relay.cert_file.clone();
+        relay_cfg.key_file = relay.key_file.clone();
+        relay_cfg.pkcs12_file = None;
+    } else if on_our_server {
+        // With our certificate, the server would take the relay for the bot
+        // and kick one of the two, so it gets one of its own, generated next
+        // to ours on first use.
+        let dir = cfg
+            .cert_file
+            .as_deref()
+            .and_then(|cert_file| Path::new(cert_file).parent())
+            .unwrap_or(Path::new(""));
+        let path = |name: String| Some(dir.join(name).to_string_lossy().into_owned());
+
+        relay_cfg.cert_file = path(format!("relay{}-cert.pem", connection));
+        relay_cfg.key_file = path(format!("relay{}-key.pem", connection));
+        relay_cfg.pkcs12_file = None;
+    }
+
+    relay_cfg
+}
+
+/**
+ * Connect the relay numbered `connection`. Voice from its channel is put in
+ * `incoming`, and chat from its channel is sent to `chat_in`.
+ */
+pub async fn start(
+    connection: usize,
+    cfg: &Config,
+    relay: &RelayConfig,
+    incoming: sound::Mixer,
+    chat_in: mpsc::Sender<String>,
+    own_sessions: OwnSessions,
+) -> anyhow::Result<Relay> {
+    let relay_cfg = relay_config(connection, cfg, relay);
+
+    info!(
+        "Relaying our channel to {:?} on {}:{}",
+        relay.channel, relay_cfg.host, relay_cfg.port
+    );
+
+    let voice_target = Arc::new(AtomicU8::new(voice::NORMAL_TALKING));
+    let (msg_sender, msg_receiver, _) = net::init(relay_cfg.clone(), voice_target).await?;
+
+    let outgoing = sound::Mixer::default();
+
+    // Nothing but relayed voice is ever sent here, so no song ever finishes.
+    let (events, _) = mpsc::channel(1);
+    let encoding = sound::EncoderSettings::new(cfg.encoder.as_ref())?;
+    let streamer = sound::AudioSender::new(
+        msg_sender.clone(),
+        events,
+        None,
+        encoding,
+        sound::Buffering::default(),
+        sound::Transition::default(),
+    );
+    streamer.relay(outgoing.clone()).await;
+
+    let (chat, chat_out) = if relay.relay_chat.unwrap_or(false) {
+        let (chat, chat_out) = mpsc::channel(16);
+        (Some(chat), Some(chat_out))
+    } else {
+        (None, None)
+    };
+
+    let relay_connection = RelayConnection {
+        connection,
+        cfg: relay_cfg,
+        msg_sender,
+        streamer,
+        incoming,
+        chat_in,
+        own_sessions,
+    };
+    tokio::spawn(async move {
+        if let Err(e) = relay_connection.run(msg_receiver, chat_out).await {
+            warn!("Relay {} stopped: {:?}", connection, e);
+        }
+    });
+
+    Ok(Relay { outgoing, chat })
+}
+
+/**
+ * The far end of a relay: its connection, and where what it hears goes.
+ */
+struct RelayConnection {
+    connection: usize,
+    cfg: Config,
+    msg_sender: MumbleMsgSink,
+    streamer: sound::AudioSender,
+    incoming: sound::Mixer,
+    chat_in: mpsc::Sender<String>,
+    own_sessions: OwnSessions,
+}
+
+impl RelayConnection {
+    /**
+     * Keep the relay in its channel, hand the voice and chat from there to our
+     * end, and send the chat from our end there.
+     */
+    async fn run(
+        self,
+        mut msg_receiver: MumbleMsgSource,
+        mut chat_out: Option<mpsc::Receiver<String>>,
+    ) -> anyhow::Result<()> {
+        let RelayConnection {
+            connection,
+            cfg,
+            msg_sender,
+            streamer,
+            incoming,
+            chat_in,
+            own_sessions,
+        } = self;
+
+        let mut server_state = ServerState::new();
+
+        let (voice_sink, voice_source) = mpsc::channel(64);
+        let (voice_frames, mut heard_frames) = broadcast::channel(256);
+        tokio::spawn(voice::voice_task(voice_source, voice_frames));
+
+        loop {
+            tokio::select! {
+                res = heard_frames.recv() => {
+                    // Lagging behind only costs us some frames.
+                    let Ok(frame) = res else {
+                        continue;
+                    };
+
+                    let in_our_channel = server_state
+                        .user(frame.session)
+                        .is_some_and(|user| Some(user.channel_id) == server_state.own_channel());
+
+                    if in_our_channel && !own_sessions.contains(&cfg.host, cfg.port, frame.session) {
+                        incoming.push(connection, frame.session, &frame.pcm);
+                    }
+                }
+                text = async { chat_out.as_mut().unwrap().recv().await }, if chat_out.is_some() => {
+                    if let (Some(text), Some(channel)) = (text, server_state.own_channel()) {
+                        net::send_text_message(&msg_sender, TextTarget::Channel(channel), text).await?;
+                    }
+                }
+                msg = msg_receiver.recv() => {
+                    let msg = match msg {
+                        Some(MumbleMsg::UDPTunnel(packet)) => {
+                            let _ = voice_sink.try_send(packet);
+                            continue;
+                        }
+                        Some(msg) => msg,
+                        None => anyhow::bail!("Connection to the server closed for good."),
+                    };
+
+                    server_state.update(&msg);
+
+                    match &msg {
+                        MumbleMsg::ServerSync(_) => {
+                            if let Some(session) = server_state.own_session() {
+                                own_sessions.set(connection, &cfg.host, cfg.port, session);
+                                crate::restore_presence(&msg_sender, &cfg, &server_state, session).await?;
+                            }
+                            streamer.set_max_bandwidth(server_state.limits().max_bandwidth).await;
+                        }
+                        MumbleMsg::ServerConfig(_) => {
+                            streamer.set_max_bandwidth(server_state.limits().max_bandwidth).await;
+                        }
+                        MumbleMsg::TextMessage(text) if chat_out.is_some() => {
+                            if let Some(name) = chat_sender(text, &server_state, &own_sessions, &cfg.host, cfg.port) {
+                                chat_in.send(format_chat(name, &text.message)).await?;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::mock_server::{tone, MockConnection, MockServer, BOT_SESSION, CHANNELS, TIMEOUT};
+
+    fn relay_cfg() -> RelayConfig {
+        RelayConfig {
+            host: None,
+            port: None,
+            username: "Relay".into(),
+            password: None,
+            channel: "Lounge".into(),
+            cert_file: None,
+            key_file: None,
+            relay_chat: Some(true),
+        }
+    }
+
+    #[tokio::test]
+    async fn relays_on_our_server_get_our_password_and_their_own_certificate() {
+        let server = MockServer::start().await;
+        let mut cfg = server.config();
+        cfg.password = Some("secret".into());
+
+        let same_server = relay_config(1, &cfg, &relay_cfg());
+        assert_eq!(same_server.password.as_deref(), Some("secret"));
+        assert_ne!(same_server.cert_file, cfg.cert_file);
+        assert_ne!(same_server.key_file, cfg.key_file);
+        assert_ne!(
+            relay_config(2, &cfg, &relay_cfg()).cert_file,
+            same_server.cert_file
+        );
+
+        let elsewhere = relay_config(
+            1,
+            &cfg,
+            &RelayConfig {
+                host: Some("other.server.example".into()),
+                ..relay_cfg()
+            },
+        );
+        assert_eq!(elsewhere.password, None);
+        assert_eq!(elsewhere.cert_file, cfg.cert_file);
+    }
+
+    #[tokio::test]
+    async fn relay_passes_voice_and_chat_both_ways() {
+        const RELAY_SESSION: u32 = 3;
+
+        let mut server = MockServer::start().await;
+        let cfg = server.config();
+
+        // The main connection is on the same server, in the relayed channel.
+        let own_sessions = OwnSessions::default();
+        own_sessions.set(MAIN_CONNECTION, &cfg.host, cfg.port, BOT_SESSION);
+
+        let incoming = sound::Mixer::default();
+        let (chat_in, mut chat_in_rx) = mpsc::channel(16);
+        let relay = start(
+            1,
+            &cfg,
+            &relay_cfg(),
+            incoming.clone(),
+            chat_in,
+            own_sessions,
+        )
+        .await
+        .unwrap();
+
+        let mut conn = server.accept().await;
+        let auth = conn.expect_login().await;
+        assert_eq!(auth.username.as_deref(), Some("Relay"));
+
+        let users = &[
+            (BOT_SESSION, 1, "Mumblebot"),
+            (2, 1, "alice"),
+            (RELAY_SESSION, 0, "Relay"),
+        ];
+        conn.sync(RELAY_SESSION, CHANNELS, users).await;
+
+        let joined = conn
+            .expect(|msg| match msg {
+                MumbleMsg::UserState(state) if state.session == Some(RELAY_SESSION) => {
+                    state.channel_id
+                }
+                _ => None,
+            })
+            .await;
+        assert_eq!(joined, 1);
+        conn.send(MumbleMsg::UserState(mumble_proto::UserState {
+            session: Some(RELAY_SESSION),
+            channel_id: Some(1),
+            ..Default::default()
+        }))
+        .await;
+
+        // Voice from our end goes out on the relay's connection.
+        relay.heard(&VoiceFrame {
+            session: 7,
+            pcm: tone(100).into(),
+        });
+        assert!(conn.voice(5).await.len() >= 5);
+
+        // Voice from the relay's channel comes in, but not our own.
+        let encoding = sound::EncoderSettings::default();
+        let mut encoder = encoding.encoder(None).unwrap();
+        let mut send_voice = async |conn: &mut MockConnection, session: u32| {
+            for frame_number in 0..10 {
+                let packet = encoding.encode(&mut encoder, &tone(10)).unwrap();
+                conn.send_voice(session, frame_number, packet).await;
+            }
+        };
+        let mixed = async |incoming: &sound::Mixer| {
+            let mut buf = vec![0; 960];
+            timeout(Duration::from_millis(300), async {
+                while !incoming.mix_into(&mut buf) {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .is_ok()
+        };
+
+        send_voice(&mut conn, BOT_SESSION).await;
+        assert!(!mixed(&incoming).await);
+
+        send_voice(&mut conn, 2).await;
+        assert!(mixed(&incoming).await);
+
+        // So does chat, both ways.
+        conn.send(MumbleMsg::TextMessage(mumble_proto::TextMessage {
+            actor: Some(2),
+            channel_id: vec![1],
+            message: "hi".into(),
+            ..Default::default()
+        }))
+        .await;
+        let chat = timeout(TIMEOUT, chat_in_rx.recv()).await.unwrap().unwrap();
+        assert_eq!(chat, "<b>alice</b>: hi");
+
+        relay.chat("bob", "hello").await;
+        let text = conn
+            .expect(|msg| match msg {
+                MumbleMsg::TextMessage(text) => Some(text),
+                _ => None,
+            })
+            .await;
+        assert_eq!(text.channel_id, [1]);
+        assert_eq!(text.message, "<b>bob</b>: hello");
+    }
+}
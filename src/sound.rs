@@ -1,62 +1,680 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use anyhow::Ok;
-use log::{debug, info};
-use opus::{Application, Channels, Encoder};
+use log::{debug, info, warn};
+use opus::{Application, Bitrate, Channels, Encoder};
 use tokio::{
-    sync::{mpsc, Mutex},
+    sync::{
+        mpsc::{self, error::TryRecvError},
+        watch, Mutex,
+    },
     task::JoinHandle,
+    time::MissedTickBehavior,
 };
 use tokio_util::sync::CancellationToken;
 
-use crate::types::{self, MumbleMsg};
+use crate::types::{self, BitrateMode, BufferConfig, EncoderConfig, MumbleMsg};
 
 const SAMPLE_RATE: u32 = 48_000;
 
-pub fn init_encoder() -> Encoder {
-    Encoder::new(SAMPLE_RATE, Channels::Stereo, Application::Audio).expect("encoder construction")
+/// Length of the audio in each voice packet we send, unless configured otherwise.
+const DEFAULT_FRAME_MS: u64 = 10;
+
+/// Packet lengths Mumble clients can play, in milliseconds.
+const FRAME_MS_CHOICES: [u64; 4] = [10, 20, 40, 60];
+
+/// Packet loss the encoder prepares for, unless configured otherwise.
+const DEFAULT_PACKET_LOSS_PERC: u8 = 15;
+
+/// Audio buffered before a song starts, unless configured otherwise.
+const DEFAULT_BUFFER_START: Duration = Duration::from_millis(200);
+
+/// Most audio read ahead of playback, unless configured otherwise.
+const DEFAULT_BUFFER_HIGH_WATER: Duration = Duration::from_secs(30);
+
+/// Audio buffered again after running dry mid-song, unless configured otherwise.
+const DEFAULT_BUFFER_UNDERRUN: Duration = Duration::from_secs(1);
+
+/// Relayed voice is held back until this much (40 ms) is buffered, to ride out jitter.
+const MIXER_PREFILL: usize = SAMPLE_RATE as usize / 25 * 2;
+
+/// Relayed voice beyond this much (200 ms) is dropped, so it never lags far behind.
+const MIXER_MAX_BUFFER: usize = SAMPLE_RATE as usize / 5 * 2;
+
+/**
+ * Bytes in each packet that go to headers rather than audio: IP, UDP, crypt
+ * and voice headers, plus TCP tunnel framing in case we fall back to it.
+ * Mirrors the calculation the Mumble client does to stay under max_bandwidth.
+ */
+const PACKET_OVERHEAD_BYTES: u32 = 20 + 8 + 4 + 1 + 2 + 12 + 1;
+
+/// Lowest bitrate we will go down to, whatever the server asks.
+const MIN_BITRATE: u32 = 8_000;
+
+/// Highest bitrate Opus supports.
+const MAX_BITRATE: u32 = 510_000;
+
+/// Largest voice packet we send.
+const MAX_PACKET_LEN: usize = 1020;
+
+/**
+ * How the audio we send is encoded, checked and filled in from the `encoder` config.
+ */
+#[derive(Debug, Clone)]
+pub struct EncoderSettings {
+    bitrate: Option<u32>,
+    bitrate_mode: BitrateMode,
+    complexity: Option<u8>,
+    fec: bool,
+    packet_loss_perc: u8,
+    mono: bool,
+    frame_ms: u64,
+}
+
+impl Default for EncoderSettings {
+    fn default() -> Self {
+        EncoderSettings {
+            bitrate: None,
+            bitrate_mode: BitrateMode::Vbr,
+            complexity: None,
+            fec: false,
+            packet_loss_perc: DEFAULT_PACKET_LOSS_PERC,
+            mono: false,
+            frame_ms: DEFAULT_FRAME_MS,
+        }
+    }
+}
+
+impl EncoderSettings {
+    pub fn new(cfg: Option<&EncoderConfig>) -> anyhow::Result<Self> {
+        let Some(cfg) = cfg else {
+            return Ok(Self::default());
+        };
+
+        let frame_ms = cfg.frame_ms.unwrap_or(DEFAULT_FRAME_MS);
+        if !FRAME_MS_CHOICES.contains(&frame_ms) {
+            anyhow::bail!("encoder frame_ms must be one of {:?}", FRAME_MS_CHOICES);
+        }
+
+        if let Some(bitrate) = cfg.bitrate {
+            if !(MIN_BITRATE..=MAX_BITRATE).contains(&bitrate) {
+                anyhow::bail!(
+                    "encoder bitrate must be between {} and {}",
+                    MIN_BITRATE,
+                    MAX_BITRATE
+                );
+            }
+        }
+
+        if cfg.complexity.is_some_and(|complexity| complexity > 10) {
+            anyhow::bail!("encoder complexity must be between 0 and 10");
+        }
+
+        let packet_loss_perc = cfg.packet_loss_perc.unwrap_or(DEFAULT_PACKET_LOSS_PERC);
+        if packet_loss_perc > 100 {
+            anyhow::bail!("encoder packet_loss_perc must be between 0 and 100");
+        }
+
+        Ok(EncoderSettings {
+            bitrate: cfg.bitrate,
+            bitrate_mode: cfg.bitrate_mode.unwrap_or(BitrateMode::Vbr),
+            complexity: cfg.complexity,
+            fec: cfg.fec.unwrap_or(false),
+            packet_loss_perc,
+            mono: cfg.mono.unwrap_or(false),
+            frame_ms,
+        })
+    }
+
+    /**
+     * Length of the audio in each packet.
+     */
+    pub fn frame(&self) -> Duration {
+        Duration::from_millis(self.frame_ms)
+    }
+
+    /**
+     * Interleaved stereo samples going into each packet.
+     */
+    fn samples_per_frame(&self) -> usize {
+        SAMPLE_RATE as usize / 1_000 * self.frame_ms as usize * 2
+    }
+
+    /**
+     * The configured bitrate, lowered as needed to keep us under a server's
+     * bandwidth limit once packet overhead is counted.
+     */
+    fn bitrate(&self, max_bandwidth: Option<u32>) -> Bitrate {
+        let overhead = (PACKET_OVERHEAD_BYTES * 8 * 1000).div_ceil(self.frame_ms as u32);
+        let allowed = max_bandwidth
+            .map(|max_bandwidth| max_bandwidth.saturating_sub(overhead).max(MIN_BITRATE));
+
+        match (self.bitrate, allowed) {
+            (Some(bitrate), Some(allowed)) if allowed < bitrate => {
+                debug!(
+                    "Lowering bitrate from {} to {} for the server's bandwidth limit",
+                    bitrate, allowed
+                );
+                Bitrate::Bits(allowed as i32)
+            }
+            (Some(bitrate), _) => Bitrate::Bits(bitrate as i32),
+            (None, Some(allowed)) => Bitrate::Bits(allowed as i32),
+            (None, None) => Bitrate::Auto,
+        }
+    }
+
+    /**
+     * A new encoder with these settings, for a server with the given bandwidth limit.
+     */
+    pub fn encoder(&self, max_bandwidth: Option<u32>) -> anyhow::Result<Encoder> {
+        let channels = if self.mono {
+            Channels::Mono
+        } else {
+            Channels::Stereo
+        };
+        let mut encoder = Encoder::new(SAMPLE_RATE, channels, Application::Audio)?;
+
+        encoder.set_bitrate(self.bitrate(max_bandwidth))?;
+        encoder.set_vbr(self.bitrate_mode != BitrateMode::Cbr)?;
+        encoder.set_vbr_constraint(self.bitrate_mode == BitrateMode::Cvbr)?;
+        if let Some(complexity) = self.complexity {
+            encoder.set_complexity(complexity as i32)?;
+        }
+        encoder.set_inband_fec(self.fec)?;
+        encoder.set_packet_loss_perc(self.packet_loss_perc as i32)?;
+
+        Ok(encoder)
+    }
+
+    /**
+     * Encode a frame of interleaved stereo PCM into a voice packet.
+     */
+    pub fn encode(&self, encoder: &mut Encoder, pcm: &[i16]) -> anyhow::Result<Vec<u8>> {
+        let mut packet = vec![0u8; MAX_PACKET_LEN];
+
+        let len = if self.mono {
+            let mono: Vec<i16> = pcm
+                .chunks(2)
+                .map(|pair| ((pair[0] as i32 + pair[1] as i32) / 2) as i16)
+                .collect();
+            encoder.encode(&mono, &mut packet)?
+        } else {
+            encoder.encode(pcm, &mut packet)?
+        };
+
+        packet.truncate(len);
+        Ok(packet)
+    }
+}
+
+/**
+ * Interleaved stereo samples in `duration` of audio.
+ */
+fn samples_in(duration: Duration) -> usize {
+    duration.as_millis() as usize * SAMPLE_RATE as usize / 1_000 * 2
+}
+
+/**
+ * Length of the audio in `samples` interleaved stereo samples.
+ */
+fn duration_of(samples: usize) -> Duration {
+    Duration::from_secs_f64(samples as f64 / 2.0 / SAMPLE_RATE as f64)
+}
+
+/**
+ * How much of a song to buffer before sending it, how far to read ahead,
+ * and how much to buffer again when the source can't keep up. In samples.
+ */
+#[derive(Debug, Clone)]
+pub struct Buffering {
+    start: usize,
+    high_water: usize,
+    underrun: usize,
+}
+
+impl Default for Buffering {
+    fn default() -> Self {
+        Buffering {
+            start: samples_in(DEFAULT_BUFFER_START),
+            high_water: samples_in(DEFAULT_BUFFER_HIGH_WATER),
+            underrun: samples_in(DEFAULT_BUFFER_UNDERRUN),
+        }
+    }
+}
+
+impl Buffering {
+    pub fn new(cfg: Option<&BufferConfig>) -> anyhow::Result<Self> {
+        let Some(cfg) = cfg else {
+            return Ok(Self::default());
+        };
+
+        let ms = |ms: Option<u64>, default| ms.map_or(default, Duration::from_millis);
+        let start = ms(cfg.start_ms, DEFAULT_BUFFER_START);
+        let high_water = ms(cfg.high_water_ms, DEFAULT_BUFFER_HIGH_WATER);
+        let underrun = ms(cfg.underrun_ms, DEFAULT_BUFFER_UNDERRUN);
+
+        if start.is_zero() || underrun.is_zero() {
+            anyhow::bail!("buffer start_ms and underrun_ms must be above zero");
+        }
+        if start > high_water || underrun > high_water {
+            anyhow::bail!("buffer start_ms and underrun_ms must not be above high_water_ms");
+        }
+
+        Ok(Buffering {
+            start: samples_in(start),
+            high_water: samples_in(high_water),
+            underrun: samples_in(underrun),
+        })
+    }
+}
+
+/**
+ * How the audio buffer is doing.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct BufferStats {
+    /// Audio buffered ahead of what is playing.
+    pub buffered: Duration,
+    /// Most audio that is read ahead.
+    pub high_water: Duration,
+    /// Times the buffer ran dry mid-song since we started.
+    pub underruns: u32,
+    /// Whether sending waits for the buffer to fill.
+    pub filling: bool,
+}
+
+/**
+ * How one song goes over into the next.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transition {
+    /// Every song is sent on its own, with a pause in between.
+    #[default]
+    Separate,
+    /// Songs are joined back to back, as on an album.
+    Gapless,
+    /// The end of a song is mixed with the start of the next, fading over from one to the other.
+    Crossfade(Duration),
+}
+
+impl Transition {
+    pub fn new(crossfade_ms: Option<u64>, gapless: Option<bool>) -> Self {
+        match (gapless, crossfade_ms) {
+            (Some(true), _) => Transition::Gapless,
+            (_, Some(ms)) if ms > 0 => Transition::Crossfade(Duration::from_millis(ms)),
+            _ => Transition::Separate,
+        }
+    }
+}
+
+/**
+ * What the send task tells the player about the songs it sends.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamEvent {
+    /// Everything of the current song was decoded, so the next one can be
+    /// handed over with `queue_next`. Only sent when songs are joined.
+    SourceEnded,
+    /// The next song took over from the current one.
+    Advanced,
+    /// The song ended, with no next song to take over.
+    Finished,
+}
+
+/**
+ * A `StreamEvent` about one song, so that the player can tell events about a
+ * song it already stopped from those about the current one.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SongEvent {
+    /// The song the event is about, as numbered by `AudioSender::start`. A song
+    /// that takes over with `StreamEvent::Advanced` is numbered one higher.
+    pub generation: u64,
+    pub event: StreamEvent,
+}
+
+/**
+ * Mix the last of one song with the first of the next, fading linearly from
+ * one to the other.
+ */
+fn crossfade(tail: &[i16], head: &[i16]) -> Vec<i16> {
+    let pairs = tail.len().min(head.len()) / 2;
+    let skip = tail.len() - pairs * 2;
+
+    let mut mixed = tail[..skip].to_vec();
+    for i in 0..pairs {
+        let fade = i as f64 / pairs as f64;
+        for channel in 0..2 {
+            let out = tail[skip + i * 2 + channel] as f64 * (1.0 - fade);
+            let into = head[i * 2 + channel] as f64 * fade;
+            mixed.push((out + into) as i16);
+        }
+    }
+    mixed.extend_from_slice(&head[pairs * 2..]);
+
+    mixed
+}
+
+/**
+ * How waiting for the buffer to fill ended.
+ */
+enum Fill {
+    Filled,
+    /// The source has nothing more to give.
+    Ended,
+    Cancelled,
+}
+
+/**
+ * Turning the music down while people in our channel talk.
+ */
+#[derive(Debug, Clone)]
+pub struct Ducking {
+    /// Whether anyone is talking right now.
+    pub talking: watch::Receiver<bool>,
+    /// Volume factor while ducked, between 0 and 1.
+    pub level: f64,
+    /// Time to fade down to `level` once someone starts talking.
+    pub attack: Duration,
+    /// Time to fade back up once everyone is quiet.
+    pub release: Duration,
+}
+
+impl Ducking {
+    /**
+     * The gain for the next frame, moving from `gain` towards the level for
+     * the current talking state at the configured rate.
+     */
+    fn next_gain(&self, gain: f64, frame: Duration) -> f64 {
+        let (target, fade_time) = if *self.talking.borrow() {
+            (self.level, self.attack)
+        } else {
+            (1.0, self.release)
+        };
+
+        let step = if fade_time.is_zero() {
+            1.0
+        } else {
+            (1.0 - self.level) * frame.as_secs_f64() / fade_time.as_secs_f64()
+        };
+
+        if target < gain {
+            (gain - step).max(target)
+        } else {
+            (gain + step).min(target)
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct MixerSpeaker {
+    buf: VecDeque<i16>,
+    /// Whether the prefill is done and the speaker is being mixed in.
+    started: bool,
+}
+
+/**
+ * Voice from several speakers, possibly on several connections, mixed
+ * together one frame at a time.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Mixer {
+    speakers: Arc<std::sync::Mutex<HashMap<(usize, u32), MixerSpeaker>>>,
+}
+
+impl Mixer {
+    /**
+     * Add voice from `session` on the connection numbered `connection`.
+     */
+    pub fn push(&self, connection: usize, session: u32, pcm: &[i16]) {
+        let mut speakers = self.speakers.lock().unwrap();
+        let speaker = speakers.entry((connection, session)).or_default();
+
+        speaker.buf.extend(pcm);
+        if speaker.buf.len() > MIXER_MAX_BUFFER {
+            let excess = speaker.buf.len() - MIXER_MAX_BUFFER;
+            speaker.buf.drain(..excess);
+        }
+    }
+
+    /**
+     * Add the next frame of everyone's voice to `buf`. Returns whether there
+     * was anyone to mix in.
+     */
+    pub fn mix_into(&self, buf: &mut [i16]) -> bool {
+        let mut mixed = false;
+
+        self.speakers.lock().unwrap().retain(|_, speaker| {
+            if !speaker.started && speaker.buf.len() < MIXER_PREFILL {
+                return true;
+            }
+
+            speaker.started = true;
+            mixed = true;
+
+            let len = buf.len().min(speaker.buf.len());
+            for (out, sample) in buf.iter_mut().zip(speaker.buf.drain(..len)) {
+                *out = out.saturating_add(sample);
+            }
+
+            // Once they run dry, they are prefilled again when they come back.
+            !speaker.buf.is_empty()
+        });
+
+        mixed
+    }
 }
 
 struct AudioSenderData {
     source: Option<mpsc::Receiver<Vec<i16>>>,
+    /// Whether everything of the current song was decoded.
+    source_ended: bool,
     sink: types::MumbleMsgSink,
-    finish_channel: mpsc::Sender<()>,
+    events: mpsc::Sender<SongEvent>,
+    /// Events for the player, sent by the send task once the lock is released.
+    pending_events: Vec<SongEvent>,
+    /// The number of the current song.
+    generation: u64,
     buf: Vec<i16>,
+    transition: Transition,
+    /// The next song, decoded ahead to join the current one.
+    next: Option<mpsc::Receiver<Vec<i16>>>,
+    next_ended: bool,
+    next_buf: Vec<i16>,
     cancel_tok: Option<CancellationToken>,
     volume: f64,
+    ducking: Option<Ducking>,
+    encoding: EncoderSettings,
+    buffering: Buffering,
+    /// Times the buffer ran dry mid-song.
+    underruns: u32,
+    /// Whether the send task waits for the buffer to fill.
+    filling: bool,
+    max_bandwidth: Option<u32>,
+    /// How much of the current song has been sent.
+    played: Duration,
+    /// Voice relayed from elsewhere, mixed in with the music.
+    relayed: Option<Mixer>,
+    /// Whether the send task is sending frames right now, rather than buffering
+    /// or stopped. Relayed voice goes out on its own while it isn't.
+    sending: Arc<AtomicBool>,
     task: Option<JoinHandle<anyhow::Result<()>>>,
 }
 
+/**
+ * Clears the sending flag once the send task ends, however it ends.
+ */
+struct NotSendingOnDrop(Arc<AtomicBool>);
+
+impl Drop for NotSendingOnDrop {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+/**
+ * Move what `source` has ready into `buf`, until it holds `limit` samples.
+ * Returns whether the source has ended.
+ */
+fn read_ahead(source: &mut mpsc::Receiver<Vec<i16>>, buf: &mut Vec<i16>, limit: usize) -> bool {
+    while buf.len() < limit {
+        match source.try_recv() {
+            Result::Ok(chunk) => buf.extend(chunk),
+            Err(TryRecvError::Empty) => return false,
+            Err(TryRecvError::Disconnected) => return true,
+        }
+    }
+
+    false
+}
+
+impl AudioSenderData {
+    /**
+     * Read ahead in the current song, returning the samples buffered and
+     * whether it has ended.
+     */
+    fn read_ahead(&mut self, limit: usize) -> (usize, bool) {
+        let ended = match self.source.as_mut() {
+            Some(source) if !self.source_ended => read_ahead(source, &mut self.buf, limit),
+            _ => true,
+        };
+
+        (self.buf.len(), ended)
+    }
+
+    /**
+     * Read ahead in the next song, if there is one.
+     */
+    fn read_ahead_next(&mut self, limit: usize) -> (usize, bool) {
+        let ended = match self.next.as_mut() {
+            Some(next) if !self.next_ended => read_ahead(next, &mut self.next_buf, limit),
+            _ => self.next_ended,
+        };
+
+        (self.next_buf.len(), ended)
+    }
+
+    /**
+     * Note an event about the current song for the player.
+     */
+    fn note(&mut self, event: StreamEvent) {
+        self.pending_events.push(SongEvent {
+            generation: self.generation,
+            event,
+        });
+    }
+
+    /**
+     * Note that the current song was decoded in full, and let the player know
+     * it can hand over the next one.
+     */
+    fn end_source(&mut self) {
+        if self.source_ended {
+            return;
+        }
+
+        self.source_ended = true;
+        if self.transition != Transition::Separate {
+            self.note(StreamEvent::SourceEnded);
+        }
+    }
+
+    /**
+     * Let the next song take over, joining what is left of the current one to it.
+     */
+    fn advance(&mut self) {
+        let head = std::mem::take(&mut self.next_buf);
+        self.buf = match self.transition {
+            Transition::Crossfade(_) => crossfade(&self.buf, &head),
+            Transition::Gapless | Transition::Separate => {
+                let mut joined = std::mem::take(&mut self.buf);
+                joined.extend(head);
+                joined
+            }
+        };
+
+        self.source = self.next.take();
+        self.source_ended = false;
+        self.played = Duration::ZERO;
+        self.note(StreamEvent::Advanced);
+        self.generation += 1;
+
+        // The player hears of the new song before it hears that it ended.
+        if std::mem::take(&mut self.next_ended) {
+            self.end_source();
+        }
+    }
+}
+
 pub struct AudioSender {
     data: Arc<Mutex<AudioSenderData>>,
 }
 
 impl AudioSender {
-    pub fn new(sink: types::MumbleMsgSink, finish_channel: mpsc::Sender<()>) -> Self {
+    pub fn new(
+        sink: types::MumbleMsgSink,
+        events: mpsc::Sender<SongEvent>,
+        ducking: Option<Ducking>,
+        encoding: EncoderSettings,
+        buffering: Buffering,
+        transition: Transition,
+    ) -> Self {
         AudioSender {
             data: Arc::new(Mutex::new(AudioSenderData {
                 source: None,
+                source_ended: false,
                 sink,
-                finish_channel,
+                events,
+                pending_events: Vec::new(),
+                generation: 0,
                 buf: Vec::new(),
+                transition,
+                next: None,
+                next_ended: false,
+                next_buf: Vec::new(),
                 cancel_tok: None,
                 volume: 0.25,
+                ducking,
+                encoding,
+                buffering,
+                underruns: 0,
+                filling: false,
+                max_bandwidth: None,
+                played: Duration::ZERO,
+                relayed: None,
+                sending: Arc::new(AtomicBool::new(false)),
                 task: None,
             })),
         }
     }
 
-    pub async fn start(&self, source: mpsc::Receiver<Vec<i16>>) -> anyhow::Result<()> {
+    /**
+     * Start sending a new song, returning the number its events are tagged with.
+     */
+    pub async fn start(&self, source: mpsc::Receiver<Vec<i16>>) -> anyhow::Result<u64> {
         let ct = CancellationToken::new();
         let ct2 = ct.clone();
 
         let mut lg = self.data.lock().await;
         lg.source = Some(source);
+        lg.source_ended = false;
         lg.buf.clear();
+        lg.next = None;
+        lg.next_buf.clear();
+        lg.pending_events.clear();
+        lg.generation += 1;
+        lg.played = Duration::ZERO;
         lg.cancel_tok = Some(ct);
         lg.task = Some(tokio::spawn(Self::send_task(self.data.clone(), ct2)));
 
-        Ok(())
+        Ok(lg.generation)
     }
 
     pub async fn resume(&self) {
@@ -92,59 +710,242 @@ impl AudioSender {
         self.data.lock().await.volume = volume;
     }
 
-    async fn send_task(
-        data: Arc<Mutex<AudioSenderData>>,
-        ct: CancellationToken,
-    ) -> anyhow::Result<()> {
-        const FRAME_MS: u64 = 10;
-        const SAMPLES_PER_CHANNEL: usize = (SAMPLE_RATE as usize) / 1_000 * (FRAME_MS as usize);
-        const SAMPLES_PER_FRAME: usize = SAMPLES_PER_CHANNEL * 2;
+    pub async fn set_max_bandwidth(&self, max_bandwidth: Option<u32>) {
+        self.data.lock().await.max_bandwidth = max_bandwidth;
+    }
 
-        debug!("Send task starting...");
+    /**
+     * Decode the next song ahead, and join it to the current one as set by
+     * the transition.
+     */
+    pub async fn queue_next(&self, source: mpsc::Receiver<Vec<i16>>) {
+        let mut lg = self.data.lock().await;
+        lg.next = Some(source);
+        lg.next_ended = false;
+        lg.next_buf.clear();
+    }
 
-        // pre-buffer the first thirty seconds
-        {
+    /**
+     * Send the voice in `mixer` along with the music, or on its own while
+     * nothing plays.
+     */
+    pub async fn relay(&self, mixer: Mixer) {
+        let (sink, sending, encoding, max_bandwidth) = {
+            let mut data = self.data.lock().await;
+            data.relayed = Some(mixer.clone());
+            (
+                data.sink.clone(),
+                data.sending.clone(),
+                data.encoding.clone(),
+                data.max_bandwidth,
+            )
+        };
+
+        tokio::spawn(Self::relay_task(
+            self.data.clone(),
+            mixer,
+            sink,
+            sending,
+            encoding,
+            max_bandwidth,
+        ));
+    }
+
+    /**
+     * How far into the current song we are.
+     */
+    pub async fn position(&self) -> Duration {
+        self.data.lock().await.played
+    }
+
+    pub async fn buffer_stats(&self) -> BufferStats {
+        let data = self.data.lock().await;
+
+        BufferStats {
+            buffered: duration_of(data.buf.len()),
+            high_water: duration_of(data.buffering.high_water),
+            underruns: data.underruns,
+            filling: data.filling,
+        }
+    }
+
+    /**
+     * Wait for the source until `target` samples are buffered.
+     */
+    async fn fill(data: &Mutex<AudioSenderData>, target: usize, ct: &CancellationToken) -> Fill {
+        // The source is taken out while waiting, so the lock stays free.
+        let mut source = {
             let mut data = data.lock().await;
-            while data.buf.len() < 48_000 * 30 * 2 {
-                let chunk = data.source.as_mut().unwrap().recv().await;
+            if data.buf.len() >= target {
+                return Fill::Filled;
+            }
+
+            let Some(source) = data.source.take() else {
+                return Fill::Ended;
+            };
+            data.filling = true;
+            source
+        };
+
+        let fill = loop {
+            tokio::select! {
+                chunk = source.recv() => {
+                    let Some(chunk) = chunk else {
+                        break Fill::Ended;
+                    };
 
-                if let Some(chunk) = chunk {
+                    let mut data = data.lock().await;
                     data.buf.extend(chunk);
-                } else {
-                    break;
+                    if data.buf.len() >= target {
+                        break Fill::Filled;
+                    }
+                }
+                _ = ct.cancelled() => {
+                    break Fill::Cancelled;
                 }
             }
+        };
+
+        let mut data = data.lock().await;
+        data.source = Some(source);
+        data.filling = false;
+        if let Fill::Ended = fill {
+            data.end_source();
+        }
+
+        fill
+    }
+
+    async fn send_task(
+        data: Arc<Mutex<AudioSenderData>>,
+        ct: CancellationToken,
+    ) -> anyhow::Result<()> {
+        debug!("Send task starting...");
+
+        let sending = data.lock().await.sending.clone();
+        let _not_sending = NotSendingOnDrop(sending.clone());
+
+        let (encoding, buffering, mut max_bandwidth) = {
+            let data = data.lock().await;
+            (
+                data.encoding.clone(),
+                data.buffering.clone(),
+                data.max_bandwidth,
+            )
+        };
+
+        let samples_per_frame = encoding.samples_per_frame();
+
+        // Less than a frame buffered is as good as none.
+        let start_fill = buffering.start.max(samples_per_frame);
+        let underrun_fill = buffering.underrun.max(samples_per_frame);
+
+        if let Fill::Cancelled = Self::fill(&data, start_fill, &ct).await {
+            return Ok(());
         }
 
         debug!("Pre-buffering done.");
 
-        let mut encoder = init_encoder();
-        encoder.set_packet_loss_perc(15)?;
+        let mut encoder = encoding.encoder(max_bandwidth)?;
 
-        // maximum frame size for Mumble
-        let mut frame_buf = vec![0u8; 1020];
+        let mut interval = tokio::time::interval(encoding.frame());
 
-        let mut interval = tokio::time::interval(Duration::from_millis(FRAME_MS));
+        let events = data.lock().await.events.clone();
 
-        let finish_channel = data.lock().await.finish_channel.clone();
+        // Volume factor from ducking, which fades between 1 and the ducked level.
+        let mut gain = 1.0;
 
         'outer: loop {
-            let mut data = data.lock().await;
+            if !Self::send_events(&data, &events, &ct).await? {
+                return Ok(());
+            }
 
-            while data.buf.len() < SAMPLES_PER_FRAME {
-                if let Some(chunk) = data.source.as_mut().unwrap().recv().await {
-                    data.buf.extend(chunk);
-                } else {
+            let underrun = {
+                let mut data = data.lock().await;
+
+                // Read ahead whatever the sources have ready, up to the high-water mark.
+                let (buf, ended) = data.read_ahead(buffering.high_water);
+                if ended {
+                    data.end_source();
+                }
+
+                let (next_buf, next_ended) = data.read_ahead_next(buffering.high_water);
+                data.next_ended |= next_ended;
+
+                if data.source_ended && data.next.is_some() {
+                    let ready = match data.transition {
+                        Transition::Crossfade(length) => {
+                            buf <= samples_in(length) && (next_buf >= buf || data.next_ended)
+                        }
+                        Transition::Gapless | Transition::Separate => false,
+                    };
+
+                    if ready || buf < samples_per_frame {
+                        debug!("Joining the next song.");
+                        data.advance();
+                    }
+                }
+
+                if data.buf.len() >= samples_per_frame {
+                    false
+                } else if data.source_ended {
                     break 'outer;
+                } else {
+                    data.underruns += 1;
+                    warn!(
+                        "Audio buffer ran dry ({} times so far), buffering {:?}.",
+                        data.underruns,
+                        duration_of(underrun_fill)
+                    );
+                    true
+                }
+            };
+
+            if underrun {
+                sending.store(false, Ordering::Relaxed);
+                if let Fill::Cancelled = Self::fill(&data, underrun_fill, &ct).await {
+                    return Ok(());
                 }
+
+                // Carry on at the normal pace, rather than catching up on the wait.
+                interval.reset();
+                continue;
             }
 
-            let mut buf: Vec<i16> = data.buf.drain(..SAMPLES_PER_FRAME).collect();
-            for val in buf.iter_mut() {
-                *val = (*val as f64 * data.volume) as i16;
+            let mut data = data.lock().await;
+
+            if data.max_bandwidth != max_bandwidth {
+                max_bandwidth = data.max_bandwidth;
+                debug!("Server bandwidth limit is now {:?}", max_bandwidth);
+                encoder.set_bitrate(encoding.bitrate(max_bandwidth))?;
             }
 
-            let encoded_len = encoder.encode(&buf, &mut frame_buf)?;
+            let next_gain = match &data.ducking {
+                Some(ducking) => ducking.next_gain(gain, encoding.frame()),
+                None => 1.0,
+            };
+
+            // Fade across the frame, so gain changes don't click.
+            let mut buf: Vec<i16> = data.buf.drain(..samples_per_frame).collect();
+            for (i, pair) in buf.chunks_mut(2).enumerate() {
+                let fade = gain + (next_gain - gain) * (i * 2) as f64 / samples_per_frame as f64;
+                for val in pair {
+                    *val = (*val as f64 * data.volume * fade) as i16;
+                }
+            }
+            gain = next_gain;
+
+            if let Some(relayed) = &data.relayed {
+                relayed.mix_into(&mut buf);
+            }
+
+            let packet = encoding.encode(&mut encoder, &buf)?;
+            data.played += encoding.frame();
+
+            // Release the lock while waiting, so the stream can still be stopped
+            // when the connection stalls and the sink fills up.
+            let sink = data.sink.clone();
+            drop(data);
 
             tokio::select! {
                 _ = interval.tick() => {}
@@ -153,13 +954,488 @@ impl AudioSender {
                 }
             }
 
-            data.sink
-                .send(MumbleMsg::UDPTunnel(Vec::from(&frame_buf[..encoded_len])))
-                .await?;
+            sending.store(true, Ordering::Relaxed);
+            tokio::select! {
+                res = sink.send(MumbleMsg::UDPTunnel(packet)) => {
+                    res?;
+                }
+                _ = ct.cancelled() => {
+                    return Ok(());
+                }
+            }
+        }
+
+        data.lock().await.note(StreamEvent::Finished);
+        if !Self::send_events(&data, &events, &ct).await? {
+            return Ok(());
         }
 
-        finish_channel.send(()).await?;
         info!("Finished song!");
         Ok(())
     }
+
+    /**
+     * Hand the events noted so far to the player, waiting for it if it is
+     * busy. Returns false if the stream was stopped meanwhile.
+     */
+    async fn send_events(
+        data: &Mutex<AudioSenderData>,
+        events: &mpsc::Sender<SongEvent>,
+        ct: &CancellationToken,
+    ) -> anyhow::Result<bool> {
+        let pending = std::mem::take(&mut data.lock().await.pending_events);
+
+        for event in pending {
+            tokio::select! {
+                res = events.send(event) => res?,
+                _ = ct.cancelled() => return Ok(false),
+            }
+        }
+
+        Ok(true)
+    }
+
+    /**
+     * Task that sends relayed voice while the send task isn't sending frames.
+     */
+    async fn relay_task(
+        data: Arc<Mutex<AudioSenderData>>,
+        relayed: Mixer,
+        sink: types::MumbleMsgSink,
+        sending: Arc<AtomicBool>,
+        encoding: EncoderSettings,
+        mut max_bandwidth: Option<u32>,
+    ) -> anyhow::Result<()> {
+        let mut encoder = encoding.encoder(max_bandwidth)?;
+
+        // Voice comes and goes, so don't catch up on ticks missed in between.
+        let mut interval = tokio::time::interval(encoding.frame());
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        // Whether we sent relayed voice last time, and owe a terminator once it stops.
+        let mut talking = false;
+
+        while !sink.is_closed() {
+            interval.tick().await;
+
+            // A song being sent mixes the relayed voice in itself.
+            if sending.load(Ordering::Relaxed) {
+                talking = false;
+                continue;
+            }
+
+            let mut buf = vec![0i16; encoding.samples_per_frame()];
+            if !relayed.mix_into(&mut buf) {
+                if talking {
+                    talking = false;
+                    sink.send(MumbleMsg::UDPTunnel(vec![])).await?;
+                }
+                continue;
+            }
+            talking = true;
+
+            let limit = data.lock().await.max_bandwidth;
+            if limit != max_bandwidth {
+                max_bandwidth = limit;
+                encoder.set_bitrate(encoding.bitrate(max_bandwidth))?;
+            }
+
+            let packet = encoding.encode(&mut encoder, &buf)?;
+            sink.send(MumbleMsg::UDPTunnel(packet)).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::{timeout, Instant};
+
+    use super::*;
+    use crate::mock_server::{connected_sender, tone, TIMEOUT};
+
+    fn settings(cfg: EncoderConfig) -> anyhow::Result<EncoderSettings> {
+        EncoderSettings::new(Some(&cfg))
+    }
+
+    fn ducking(attack_ms: u64, release_ms: u64) -> (watch::Sender<bool>, Ducking) {
+        let (talking, talking_rx) = watch::channel(false);
+        let ducking = Ducking {
+            talking: talking_rx,
+            level: 0.2,
+            attack: Duration::from_millis(attack_ms),
+            release: Duration::from_millis(release_ms),
+        };
+        (talking, ducking)
+    }
+
+    #[test]
+    fn ducking_fades_at_the_attack_and_release_rates() {
+        let (talking, ducking) = ducking(100, 400);
+        let frame = Duration::from_millis(10);
+
+        // Nobody talking: full volume stays put.
+        assert_eq!(ducking.next_gain(1.0, frame), 1.0);
+
+        // 0.8 down over 100 ms is 0.08 a frame, and it stops at the level.
+        talking.send_replace(true);
+        assert!((ducking.next_gain(1.0, frame) - 0.92).abs() < 1e-9);
+        assert_eq!(ducking.next_gain(0.25, frame), 0.2);
+        assert_eq!(ducking.next_gain(0.2, frame), 0.2);
+
+        // 0.8 up over 400 ms is 0.02 a frame, and it stops at 1.
+        talking.send_replace(false);
+        assert!((ducking.next_gain(0.2, frame) - 0.22).abs() < 1e-9);
+        assert_eq!(ducking.next_gain(0.99, frame), 1.0);
+    }
+
+    #[test]
+    fn ducking_without_fade_time_jumps() {
+        let (talking, ducking) = ducking(0, 0);
+        let frame = Duration::from_millis(20);
+
+        talking.send_replace(true);
+        assert_eq!(ducking.next_gain(1.0, frame), 0.2);
+
+        talking.send_replace(false);
+        assert_eq!(ducking.next_gain(0.2, frame), 1.0);
+    }
+
+    #[test]
+    fn crossfade_fades_from_the_tail_into_the_head() {
+        // The tail is longer than the head by one stereo pair, which is kept as is.
+        let tail = vec![1000; 10];
+        let head = vec![-1000; 8];
+
+        let mixed = crossfade(&tail, &head);
+        assert_eq!(mixed.len(), 10);
+        assert_eq!(&mixed[..4], &[1000, 1000, 1000, 1000]);
+        assert_eq!(&mixed[8..], &[-500, -500]);
+
+        // A head longer than the tail carries on after it.
+        let mixed = crossfade(&head[..4], &tail);
+        assert_eq!(mixed.len(), 10);
+        assert_eq!(&mixed[4..], &[1000; 6]);
+    }
+
+    #[test]
+    fn gapless_wins_over_crossfade() {
+        assert_eq!(Transition::new(None, None), Transition::Separate);
+        assert_eq!(Transition::new(Some(0), None), Transition::Separate);
+        assert_eq!(
+            Transition::new(Some(3000), Some(false)),
+            Transition::Crossfade(Duration::from_secs(3))
+        );
+        assert_eq!(Transition::new(Some(3000), Some(true)), Transition::Gapless);
+    }
+
+    #[test]
+    fn invalid_buffering_is_rejected() {
+        for cfg in [
+            BufferConfig {
+                start_ms: Some(0),
+                ..Default::default()
+            },
+            BufferConfig {
+                underrun_ms: Some(0),
+                ..Default::default()
+            },
+            BufferConfig {
+                start_ms: Some(2000),
+                high_water_ms: Some(1000),
+                ..Default::default()
+            },
+            BufferConfig {
+                underrun_ms: Some(2000),
+                high_water_ms: Some(1000),
+                ..Default::default()
+            },
+        ] {
+            assert!(Buffering::new(Some(&cfg)).is_err(), "{:?}", cfg);
+        }
+
+        let buffering = Buffering::new(Some(&BufferConfig {
+            start_ms: Some(20),
+            high_water_ms: Some(1000),
+            underrun_ms: Some(10),
+        }))
+        .unwrap();
+        assert_eq!(buffering.start, 1920);
+        assert_eq!(buffering.underrun, 960);
+        assert_eq!(buffering.high_water, 96_000);
+    }
+
+    #[test]
+    fn bitrate_is_lowered_to_fit_the_bandwidth_limit() {
+        let encoding = settings(EncoderConfig {
+            bitrate: Some(96_000),
+            ..Default::default()
+        })
+        .unwrap();
+
+        // 10 ms packets carry 48 bytes of overhead each: 38.4 kbit/s.
+        assert_eq!(encoding.bitrate(None), Bitrate::Bits(96_000));
+        assert_eq!(encoding.bitrate(Some(200_000)), Bitrate::Bits(96_000));
+        assert_eq!(encoding.bitrate(Some(72_000)), Bitrate::Bits(33_600));
+        assert_eq!(
+            encoding.bitrate(Some(1_000)),
+            Bitrate::Bits(MIN_BITRATE as i32)
+        );
+    }
+
+    #[test]
+    fn longer_frames_leave_more_room_for_audio() {
+        let encoding = settings(EncoderConfig {
+            frame_ms: Some(60),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(encoding.bitrate(None), Bitrate::Auto);
+        assert_eq!(encoding.bitrate(Some(72_000)), Bitrate::Bits(65_600));
+        assert_eq!(encoding.samples_per_frame(), 2880 * 2);
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        for cfg in [
+            EncoderConfig {
+                frame_ms: Some(30),
+                ..Default::default()
+            },
+            EncoderConfig {
+                bitrate: Some(1_000_000),
+                ..Default::default()
+            },
+            EncoderConfig {
+                complexity: Some(11),
+                ..Default::default()
+            },
+            EncoderConfig {
+                packet_loss_perc: Some(101),
+                ..Default::default()
+            },
+        ] {
+            assert!(settings(cfg.clone()).is_err(), "{:?}", cfg);
+        }
+    }
+
+    #[test]
+    fn mono_packets_are_mono() {
+        let encoding = settings(EncoderConfig {
+            mono: Some(true),
+            frame_ms: Some(20),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut encoder = encoding.encoder(None).unwrap();
+
+        let packet = encoding
+            .encode(&mut encoder, &vec![1000; encoding.samples_per_frame()])
+            .unwrap();
+
+        assert_eq!(
+            opus::packet::get_nb_channels(&packet).unwrap(),
+            Channels::Mono
+        );
+        assert_eq!(
+            opus::packet::get_nb_samples(&packet, SAMPLE_RATE).unwrap(),
+            960
+        );
+    }
+
+    #[tokio::test]
+    async fn relayed_voice_goes_out_while_a_song_buffers() {
+        let (sink, mut sent) = mpsc::channel(64);
+        let (finish, _) = mpsc::channel(1);
+        let sender = AudioSender::new(
+            sink,
+            finish,
+            None,
+            EncoderSettings::default(),
+            Buffering::default(),
+            Transition::default(),
+        );
+
+        let mixer = Mixer::default();
+        sender.relay(mixer.clone()).await;
+
+        // A song that never gets past buffering.
+        let (_pcm_wr, pcm_rd) = mpsc::channel(1);
+        sender.start(pcm_rd).await.unwrap();
+
+        let frame = EncoderSettings::default().samples_per_frame();
+        mixer.push(1, 2, &vec![1000; frame * 10]);
+
+        let mut frames = 0;
+        loop {
+            let msg = timeout(Duration::from_secs(1), sent.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            let MumbleMsg::UDPTunnel(frame) = msg else {
+                panic!("unexpected {:?}", msg);
+            };
+
+            // Then the terminator, once the relayed voice stops.
+            if frame.is_empty() {
+                break;
+            }
+            frames += 1;
+        }
+        assert_eq!(frames, 10);
+    }
+
+    #[tokio::test]
+    async fn longer_frames_advance_the_sequence_by_their_length() {
+        let encoding = settings(EncoderConfig {
+            frame_ms: Some(20),
+            ..Default::default()
+        })
+        .unwrap();
+        let (_server, mut conn, streamer, _finish_rd) =
+            connected_sender(encoding, Buffering::default(), Transition::default()).await;
+
+        let (pcm_wr, pcm_rd) = mpsc::channel(1);
+        pcm_wr.send(tone(100)).await.unwrap();
+        drop(pcm_wr);
+        streamer.start(pcm_rd).await.unwrap();
+
+        let voice = conn.voice(5).await;
+        let seq_nrs: Vec<u64> = voice
+            .iter()
+            .map(|captured| captured.packet.seq_nr)
+            .collect();
+        assert_eq!(seq_nrs, [0, 2, 4, 6, 8]);
+    }
+
+    #[tokio::test]
+    async fn playback_starts_quickly_and_recovers_from_underruns() {
+        let buffering = Buffering::new(Some(&BufferConfig {
+            start_ms: Some(100),
+            high_water_ms: Some(1000),
+            underrun_ms: Some(100),
+        }))
+        .unwrap();
+        let (_server, mut conn, streamer, mut finish_rd) =
+            connected_sender(EncoderSettings::default(), buffering, Transition::default()).await;
+
+        // 300 ms of audio right away, then a stall, then 200 ms more.
+        let (pcm_wr, pcm_rd) = mpsc::channel(4);
+        let started = Instant::now();
+        streamer.start(pcm_rd).await.unwrap();
+        tokio::spawn(async move {
+            for _ in 0..6 {
+                pcm_wr.send(tone(50)).await.unwrap();
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            for _ in 0..4 {
+                pcm_wr.send(tone(50)).await.unwrap();
+            }
+        });
+
+        let first = conn.voice(1).await[0].received;
+        assert!(
+            first - started < Duration::from_secs(1),
+            "{:?}",
+            first - started
+        );
+
+        timeout(TIMEOUT, finish_rd.recv()).await.unwrap();
+        assert_eq!(conn.voice(50).await.len(), 50);
+
+        let stats = streamer.buffer_stats().await;
+        assert_eq!(stats.underruns, 1);
+        assert!(!stats.filling);
+    }
+
+    /**
+     * Play two 100 ms songs joined by `transition`, handing the second one
+     * over as the player would, and check they make up `frames` frames.
+     */
+    async fn play_joined(transition: Transition, frames: usize) {
+        let (_server, mut conn, streamer, mut events) =
+            connected_sender(EncoderSettings::default(), Buffering::default(), transition).await;
+
+        let (pcm_wr, pcm_rd) = mpsc::channel(1);
+        pcm_wr.send(tone(100)).await.unwrap();
+        drop(pcm_wr);
+        let generation = streamer.start(pcm_rd).await.unwrap();
+
+        let mut next_event = async || timeout(TIMEOUT, events.recv()).await.unwrap().unwrap();
+        let event = |generation, event| SongEvent { generation, event };
+        assert_eq!(
+            next_event().await,
+            event(generation, StreamEvent::SourceEnded)
+        );
+
+        let (pcm_wr, pcm_rd) = mpsc::channel(1);
+        pcm_wr.send(tone(100)).await.unwrap();
+        drop(pcm_wr);
+        streamer.queue_next(pcm_rd).await;
+
+        assert_eq!(next_event().await, event(generation, StreamEvent::Advanced));
+        assert_eq!(
+            next_event().await,
+            event(generation + 1, StreamEvent::SourceEnded)
+        );
+        assert_eq!(
+            next_event().await,
+            event(generation + 1, StreamEvent::Finished)
+        );
+
+        let seq_nrs: Vec<u64> = conn
+            .voice(frames)
+            .await
+            .iter()
+            .map(|captured| captured.packet.seq_nr)
+            .collect();
+        assert_eq!(seq_nrs, (0..frames as u64).collect::<Vec<_>>());
+
+        // Nothing comes after the joined songs.
+        assert!(timeout(Duration::from_millis(200), conn.voice(frames + 1))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn gapless_songs_are_joined_back_to_back() {
+        play_joined(Transition::Gapless, 20).await;
+    }
+
+    #[tokio::test]
+    async fn crossfaded_songs_overlap() {
+        play_joined(Transition::Crossfade(Duration::from_millis(50)), 15).await;
+    }
+
+    #[tokio::test]
+    async fn restarted_songs_get_a_new_generation() {
+        let (_server, _conn, streamer, mut events) = connected_sender(
+            EncoderSettings::default(),
+            Buffering::default(),
+            Transition::default(),
+        )
+        .await;
+
+        // Never ends, so it can only be stopped.
+        let (_pcm_wr, pcm_rd) = mpsc::channel(1);
+        let stopped = streamer.start(pcm_rd).await.unwrap();
+        streamer.stop().await.unwrap();
+
+        let (pcm_wr, pcm_rd) = mpsc::channel(1);
+        pcm_wr.send(tone(100)).await.unwrap();
+        drop(pcm_wr);
+        let generation = streamer.start(pcm_rd).await.unwrap();
+        assert!(generation > stopped);
+
+        let event = timeout(TIMEOUT, events.recv()).await.unwrap().unwrap();
+        assert_eq!(
+            event,
+            SongEvent {
+                generation,
+                event: StreamEvent::Finished
+            }
+        );
+    }
 }
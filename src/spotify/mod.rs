@@ -48,6 +48,8 @@ impl From<rspotify::model::FullTrack> for Song {
             name: format!("{} - {}", val.artists[0].name, val.name),
             id: val.id.unwrap().uri(),
             song_type: crate::types::SongType::Spotify,
+            duration: val.duration.to_std().ok(),
+            image_url: val.album.images.first().map(|image| image.url.clone()),
         }
     }
 }
@@ -58,6 +60,10 @@ impl From<rspotify::model::SimplifiedTrack> for Song {
             name: format!("{} - {}", val.artists[0].name, val.name),
             id: val.id.unwrap().uri(),
             song_type: crate::types::SongType::Spotify,
+            duration: val.duration.to_std().ok(),
+            image_url: val
+                .album
+                .and_then(|album| album.images.first().map(|image| image.url.clone())),
         }
     }
 }
@@ -116,6 +122,7 @@ pub async fn get_playlist_tracks_by_id(
     Ok(tracks)
 }
 
+#[allow(dead_code)]
 pub async fn get_album_tracks_by_id(config: &Config, album_uri: &str) -> anyhow::Result<Vec<Song>> {
     let spot = get_rspotify_session(config).await?;
 
@@ -0,0 +1,601 @@
+use std::collections::{HashMap, HashSet};
+
+use log::{debug, info};
+
+use crate::{mumble_proto, types::MumbleMsg};
+
+/// Channel ID of the root channel, which every server has.
+pub const ROOT_CHANNEL: u32 = 0;
+
+#[derive(Debug, Clone, Default)]
+pub struct Channel {
+    pub parent: Option<u32>,
+    pub name: String,
+    pub links: HashSet<u32>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct User {
+    pub session: u32,
+    pub user_id: Option<u32>,
+    pub name: String,
+    pub channel_id: u32,
+    pub hash: Option<String>,
+    pub comment: Option<String>,
+    pub mute: bool,
+    pub deaf: bool,
+    pub suppress: bool,
+    pub self_mute: bool,
+    pub self_deaf: bool,
+}
+
+impl User {
+    /**
+     * Short human-readable description of the user's mute/deaf flags.
+     */
+    pub fn flags(&self) -> Vec<&'static str> {
+        let mut flags = vec![];
+
+        if self.deaf {
+            flags.push("deafened");
+        } else if self.self_deaf {
+            flags.push("self-deafened");
+        }
+
+        if self.mute {
+            flags.push("muted");
+        } else if self.suppress {
+            flags.push("suppressed");
+        } else if self.self_mute {
+            flags.push("self-muted");
+        }
+
+        flags
+    }
+}
+
+/**
+ * Limits the server puts on its clients, from the ServerSync and ServerConfig messages.
+ */
+#[derive(Debug, Clone)]
+pub struct ServerLimits {
+    /// Maximum text message length, if limited.
+    pub message_length: Option<u32>,
+    /// Maximum length of text messages with images in them, if limited.
+    pub image_message_length: Option<u32>,
+    pub allow_html: bool,
+    /// Maximum voice bandwidth in bits per second, including packet overhead.
+    pub max_bandwidth: Option<u32>,
+    pub max_users: Option<u32>,
+}
+
+impl Default for ServerLimits {
+    fn default() -> Self {
+        ServerLimits {
+            message_length: None,
+            image_message_length: None,
+            allow_html: true,
+            max_bandwidth: None,
+            max_users: None,
+        }
+    }
+}
+
+impl ServerLimits {
+    pub fn update(&mut self, msg: &MumbleMsg) {
+        // A length of zero means no limit.
+        let limit = |value: Option<u32>| value.filter(|&v| v > 0);
+
+        match msg {
+            MumbleMsg::ServerSync(sync) if sync.max_bandwidth.is_some() => {
+                self.max_bandwidth = limit(sync.max_bandwidth);
+            }
+            MumbleMsg::ServerConfig(config) => {
+                if config.max_bandwidth.is_some() {
+                    self.max_bandwidth = limit(config.max_bandwidth);
+                }
+                if config.message_length.is_some() {
+                    self.message_length = limit(config.message_length);
+                }
+                if config.image_message_length.is_some() {
+                    self.image_message_length = limit(config.image_message_length);
+                }
+                if let Some(allow_html) = config.allow_html {
+                    self.allow_html = allow_html;
+                }
+                if config.max_users.is_some() {
+                    self.max_users = limit(config.max_users);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/**
+ * What we had set up for ourselves on a previous connection, so it can be
+ * restored after reconnecting.
+ */
+#[derive(Debug, Clone)]
+pub struct Presence {
+    pub channel_path: String,
+    pub comment: Option<String>,
+    pub self_mute: bool,
+    pub self_deaf: bool,
+}
+
+/**
+ * Our view of the server: the channel tree, the connected users and our own session.
+ * Built from the ChannelState/UserState messages sent during login and kept
+ * up to date with every later change.
+ */
+#[derive(Debug, Default)]
+pub struct ServerState {
+    channels: HashMap<u32, Channel>,
+    users: HashMap<u32, User>,
+    own_session: Option<u32>,
+    previous_presence: Option<Presence>,
+    limits: ServerLimits,
+}
+
+impl ServerState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, msg: &MumbleMsg) {
+        match msg {
+            // The server's Version is the first message on every (re)connection,
+            // after which it sends us the complete state again.
+            MumbleMsg::Version(_) => {
+                let previous_presence = self.presence().or(self.previous_presence.take());
+                *self = ServerState::new();
+                self.previous_presence = previous_presence;
+            }
+            MumbleMsg::ServerSync(sync) => {
+                self.own_session = sync.session;
+                self.limits.update(msg);
+            }
+            MumbleMsg::ServerConfig(_) => self.limits.update(msg),
+            MumbleMsg::ChannelState(channel_state) => self.update_channel(channel_state),
+            MumbleMsg::ChannelRemove(channel_remove) => {
+                self.remove_channel(channel_remove.channel_id)
+            }
+            MumbleMsg::UserState(user_state) => self.update_user(user_state),
+            MumbleMsg::UserRemove(user_remove) => self.remove_user(user_remove),
+            _ => {}
+        }
+    }
+
+    fn update_channel(&mut self, msg: &mumble_proto::ChannelState) {
+        let Some(id) = msg.channel_id else {
+            return;
+        };
+
+        let channel = self.channels.entry(id).or_default();
+
+        if msg.parent.is_some() {
+            channel.parent = msg.parent;
+        }
+
+        if let Some(name) = &msg.name {
+            channel.name = name.clone();
+        }
+
+        if !msg.links.is_empty() {
+            channel.links = msg.links.iter().copied().collect();
+        }
+
+        channel.links.extend(msg.links_add.iter().copied());
+
+        for link in &msg.links_remove {
+            channel.links.remove(link);
+        }
+    }
+
+    fn remove_channel(&mut self, id: u32) {
+        let Some(channel) = self.channels.remove(&id) else {
+            return;
+        };
+        debug!("Channel {:?} removed.", channel.name);
+
+        // The server moves users out first, but don't leave any in a channel that is gone.
+        let parent = channel.parent.unwrap_or(ROOT_CHANNEL);
+        for user in self.users.values_mut().filter(|user| user.channel_id == id) {
+            user.channel_id = parent;
+        }
+
+        for channel in self.channels.values_mut() {
+            channel.links.remove(&id);
+        }
+    }
+
+    fn update_user(&mut self, msg: &mumble_proto::UserState) {
+        let Some(session) = msg.session else {
+            return;
+        };
+
+        let user = self.users.entry(session).or_insert_with(|| User {
+            session,
+            ..Default::default()
+        });
+
+        if let Some(name) = &msg.name {
+            user.name = name.clone();
+        }
+
+        if msg.user_id.is_some() {
+            user.user_id = msg.user_id;
+        }
+
+        if let Some(channel_id) = msg.channel_id {
+            user.channel_id = channel_id;
+        }
+
+        if msg.comment.is_some() {
+            user.comment = msg.comment.clone();
+        }
+
+        if msg.hash.is_some() {
+            user.hash = msg.hash.clone();
+            debug!(
+                "User {:?} (session {}) has certificate hash {:?}",
+                user.name, session, user.hash
+            );
+        }
+
+        let flags = [
+            (msg.mute, &mut user.mute),
+            (msg.deaf, &mut user.deaf),
+            (msg.suppress, &mut user.suppress),
+            (msg.self_mute, &mut user.self_mute),
+            (msg.self_deaf, &mut user.self_deaf),
+        ];
+
+        for (value, flag) in flags {
+            if let Some(value) = value {
+                *flag = value;
+            }
+        }
+    }
+
+    fn remove_user(&mut self, msg: &mumble_proto::UserRemove) {
+        if let Some(user) = self.users.remove(&msg.session) {
+            debug!("User {:?} (session {}) left.", user.name, user.session);
+        }
+
+        if Some(msg.session) == self.own_session {
+            info!("We were removed from the server: {:?}", msg.reason);
+            self.own_session = None;
+        }
+    }
+
+    pub fn limits(&self) -> &ServerLimits {
+        &self.limits
+    }
+
+    pub fn own_session(&self) -> Option<u32> {
+        self.own_session
+    }
+
+    pub fn user(&self, session: u32) -> Option<&User> {
+        self.users.get(&session)
+    }
+
+    /**
+     * The channel we are in, once we know.
+     */
+    pub fn own_channel(&self) -> Option<u32> {
+        Some(self.user(self.own_session?)?.channel_id)
+    }
+
+    fn presence(&self) -> Option<Presence> {
+        let own_user = self.user(self.own_session?)?;
+
+        Some(Presence {
+            channel_path: self.channel_path(own_user.channel_id),
+            comment: own_user.comment.clone(),
+            self_mute: own_user.self_mute,
+            self_deaf: own_user.self_deaf,
+        })
+    }
+
+    /**
+     * Our presence at the end of the previous connection, if there was one.
+     */
+    pub fn previous_presence(&self) -> Option<&Presence> {
+        self.previous_presence.as_ref()
+    }
+
+    /**
+     * Look up a connected user by name, ignoring case.
+     */
+    pub fn find_user(&self, name: &str) -> Option<&User> {
+        self.users
+            .values()
+            .find(|user| user.name.to_lowercase() == name.to_lowercase())
+    }
+
+    /**
+     * Users in a channel, sorted by name.
+     */
+    pub fn users_in_channel(&self, channel_id: u32) -> Vec<&User> {
+        let mut users: Vec<&User> = self
+            .users
+            .values()
+            .filter(|user| user.channel_id == channel_id)
+            .collect();
+
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+
+        users
+    }
+
+    /**
+     * Sub-channels of a channel.
+     */
+    pub fn children(&self, channel_id: u32) -> Vec<u32> {
+        self.channels
+            .iter()
+            .filter(|(&id, channel)| channel.parent == Some(channel_id) && id != channel_id)
+            .map(|(&id, _)| id)
+            .collect()
+    }
+
+    /**
+     * Look up a channel by a slash-separated path like `Music/Lounge`, starting
+     * at the root channel. Names are matched case-insensitively. A single name
+     * that is not a direct child of the root is looked up anywhere in the tree.
+     */
+    pub fn find_channel(&self, path: &str) -> Option<u32> {
+        let matches = |id: &u32, name: &str| {
+            self.channels
+                .get(id)
+                .is_some_and(|channel| channel.name.to_lowercase() == name.to_lowercase())
+        };
+
+        let components: Vec<&str> = path
+            .split('/')
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .collect();
+
+        let mut current = ROOT_CHANNEL;
+        let mut found = true;
+
+        for component in &components {
+            match self
+                .children(current)
+                .into_iter()
+                .find(|id| matches(id, component))
+            {
+                Some(child) => current = child,
+                None => {
+                    found = false;
+                    break;
+                }
+            }
+        }
+
+        if found {
+            return Some(current);
+        }
+
+        if let [name] = components.as_slice() {
+            return self.channels.keys().copied().find(|id| matches(id, name));
+        }
+
+        None
+    }
+
+    /**
+     * Slash-separated path of a channel, leaving out the root channel.
+     */
+    pub fn channel_path(&self, channel_id: u32) -> String {
+        let mut names = vec![];
+        let mut current = self.channels.get(&channel_id);
+
+        while let Some(channel) = current {
+            let Some(parent) = channel.parent else {
+                break;
+            };
+
+            names.push(channel.name.as_str());
+
+            // guard against a malformed tree
+            if names.len() > self.channels.len() {
+                break;
+            }
+
+            current = self.channels.get(&parent);
+        }
+
+        if names.is_empty() {
+            return String::from("/");
+        }
+
+        names.reverse();
+        names.join("/")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(id: u32, parent: Option<u32>, name: &str) -> MumbleMsg {
+        MumbleMsg::ChannelState(mumble_proto::ChannelState {
+            channel_id: Some(id),
+            parent,
+            name: Some(name.into()),
+            ..Default::default()
+        })
+    }
+
+    fn user(session: u32, channel_id: u32, name: &str) -> MumbleMsg {
+        MumbleMsg::UserState(mumble_proto::UserState {
+            session: Some(session),
+            channel_id: Some(channel_id),
+            name: Some(name.into()),
+            ..Default::default()
+        })
+    }
+
+    /**
+     * Root, with Music/Lounge and Games under it, and us and alice in Lounge.
+     */
+    fn synced() -> ServerState {
+        let mut state = ServerState::new();
+
+        for msg in [
+            channel(0, None, "Root"),
+            channel(1, Some(0), "Music"),
+            channel(2, Some(1), "Lounge"),
+            channel(3, Some(0), "Games"),
+            user(10, 2, "Mumblebot"),
+            user(11, 2, "alice"),
+            MumbleMsg::ServerSync(mumble_proto::ServerSync {
+                session: Some(10),
+                ..Default::default()
+            }),
+        ] {
+            state.update(&msg);
+        }
+
+        state
+    }
+
+    #[test]
+    fn channel_paths_lead_from_the_root() {
+        let state = synced();
+
+        assert_eq!(state.channel_path(2), "Music/Lounge");
+        assert_eq!(state.channel_path(3), "Games");
+        assert_eq!(state.channel_path(ROOT_CHANNEL), "/");
+    }
+
+    #[test]
+    fn channels_are_found_by_path_or_name() {
+        let state = synced();
+
+        assert_eq!(state.find_channel("Music/Lounge"), Some(2));
+        assert_eq!(state.find_channel("/music/LOUNGE/"), Some(2));
+        assert_eq!(state.find_channel("lounge"), Some(2));
+        assert_eq!(state.find_channel("Games/Lounge"), None);
+        assert_eq!(state.find_channel(""), Some(ROOT_CHANNEL));
+        assert_eq!(state.own_channel(), Some(2));
+    }
+
+    #[test]
+    fn partial_user_updates_keep_the_rest() {
+        let mut state = synced();
+
+        state.update(&MumbleMsg::UserState(mumble_proto::UserState {
+            session: Some(11),
+            self_mute: Some(true),
+            ..Default::default()
+        }));
+        state.update(&MumbleMsg::UserState(mumble_proto::UserState {
+            session: Some(11),
+            channel_id: Some(3),
+            ..Default::default()
+        }));
+
+        let alice = state.user(11).unwrap();
+        assert_eq!(alice.name, "alice");
+        assert_eq!(alice.channel_id, 3);
+        assert!(alice.self_mute);
+        assert_eq!(alice.flags(), ["self-muted"]);
+        assert_eq!(state.find_user("ALICE").map(|user| user.session), Some(11));
+    }
+
+    #[test]
+    fn links_are_set_added_and_removed() {
+        let mut state = synced();
+        let links = |state: &ServerState, id| {
+            let mut links: Vec<u32> = state.channels[&id].links.iter().copied().collect();
+            links.sort();
+            links
+        };
+
+        state.update(&MumbleMsg::ChannelState(mumble_proto::ChannelState {
+            channel_id: Some(1),
+            links: vec![2],
+            ..Default::default()
+        }));
+        state.update(&MumbleMsg::ChannelState(mumble_proto::ChannelState {
+            channel_id: Some(1),
+            links_add: vec![3],
+            ..Default::default()
+        }));
+        assert_eq!(links(&state, 1), [2, 3]);
+        assert_eq!(state.channels[&1].name, "Music");
+
+        state.update(&MumbleMsg::ChannelState(mumble_proto::ChannelState {
+            channel_id: Some(1),
+            links_remove: vec![2],
+            ..Default::default()
+        }));
+        assert_eq!(links(&state, 1), [3]);
+
+        // Links to a removed channel go with it.
+        state.update(&MumbleMsg::ChannelRemove(mumble_proto::ChannelRemove {
+            channel_id: 3,
+        }));
+        assert!(links(&state, 1).is_empty());
+    }
+
+    #[test]
+    fn users_in_a_removed_channel_move_to_its_parent() {
+        let mut state = synced();
+
+        state.update(&MumbleMsg::ChannelRemove(mumble_proto::ChannelRemove {
+            channel_id: 2,
+        }));
+
+        assert!(!state.channels.contains_key(&2));
+        assert_eq!(state.own_channel(), Some(1));
+        let names: Vec<&str> = state
+            .users_in_channel(1)
+            .iter()
+            .map(|user| user.name.as_str())
+            .collect();
+        assert_eq!(names, ["Mumblebot", "alice"]);
+    }
+
+    #[test]
+    fn removed_users_are_forgotten() {
+        let mut state = synced();
+
+        state.update(&MumbleMsg::UserRemove(mumble_proto::UserRemove {
+            session: 11,
+            ..Default::default()
+        }));
+        assert!(state.user(11).is_none());
+        assert_eq!(state.own_session(), Some(10));
+
+        state.update(&MumbleMsg::UserRemove(mumble_proto::UserRemove {
+            session: 10,
+            ..Default::default()
+        }));
+        assert_eq!(state.own_session(), None);
+        assert_eq!(state.own_channel(), None);
+    }
+
+    #[test]
+    fn reconnecting_starts_over_but_remembers_our_presence() {
+        let mut state = synced();
+
+        state.update(&MumbleMsg::Version(Default::default()));
+
+        assert!(state.user(11).is_none());
+        assert!(state.channels.is_empty());
+        assert_eq!(state.own_session(), None);
+        assert_eq!(
+            state
+                .previous_presence()
+                .map(|presence| presence.channel_path.as_str()),
+            Some("Music/Lounge")
+        );
+    }
+}
@@ -0,0 +1,250 @@
+use crate::state::ServerLimits;
+
+/**
+ * Length of a message as the server measures it, in UTF-16 code units.
+ */
+pub fn message_length(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+/**
+ * Escape text for inclusion in an HTML message.
+ */
+pub fn escape_html(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '&' => output.push_str("&amp;"),
+            '"' => output.push_str("&quot;"),
+            _ => output.push(c),
+        }
+    }
+
+    output
+}
+
+/**
+ * Turn an HTML message into plain text, for servers that don't allow HTML.
+ * Line-breaking tags become newlines, other tags are dropped and the common
+ * entities are decoded.
+ */
+pub fn html_to_plain(html: &str) -> String {
+    let mut output = String::new();
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        output.push_str(&rest[..start]);
+
+        let Some(end) = rest[start..].find('>') else {
+            rest = &rest[start..];
+            break;
+        };
+
+        let tag = rest[start + 1..start + end]
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+
+        if matches!(tag.as_str(), "br" | "p" | "div" | "li" | "tr") && !output.ends_with('\n') {
+            output.push('\n');
+        }
+
+        rest = &rest[start + end + 1..];
+    }
+
+    output.push_str(rest);
+
+    output
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+        .trim()
+        .to_string()
+}
+
+/**
+ * The pieces of a line that must stay together: in HTML, whole tags and
+ * entities, and otherwise single characters.
+ */
+fn atoms(line: &str, html: bool) -> Vec<&str> {
+    let mut atoms = vec![];
+    let mut rest = line;
+
+    while let Some(c) = rest.chars().next() {
+        let len = match c {
+            '<' if html => rest.find('>').map(|end| end + 1),
+            '&' if html => rest
+                .find(';')
+                .filter(|&end| {
+                    rest[1..end]
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '#')
+                })
+                .map(|end| end + 1),
+            _ => None,
+        };
+
+        let (atom, after) = rest.split_at(len.unwrap_or(c.len_utf8()));
+        atoms.push(atom);
+        rest = after;
+    }
+
+    atoms
+}
+
+/**
+ * Split a line that is too long on its own at spaces, or anywhere if a
+ * single word doesn't fit either. Tags and entities in HTML are never split.
+ */
+fn split_line(line: &str, max_len: usize, html: bool) -> Vec<String> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    let mut current_len = 0;
+
+    let atoms = atoms(line, html);
+    for word in atoms.split(|&atom| atom == " ") {
+        let word_len: usize = word.iter().map(|atom| message_length(atom)).sum();
+        let needed = word_len + if current.is_empty() { 0 } else { 1 };
+
+        if current_len + needed <= max_len {
+            if !current.is_empty() {
+                current.push(' ');
+            }
+            current.extend(word.iter().copied());
+            current_len += needed;
+            continue;
+        }
+
+        if !current.is_empty() {
+            parts.push(std::mem::take(&mut current));
+            current_len = 0;
+        }
+
+        for atom in word {
+            let atom_len = message_length(atom);
+            if current_len + atom_len > max_len && !current.is_empty() {
+                parts.push(std::mem::take(&mut current));
+                current_len = 0;
+            }
+            current.push_str(atom);
+            current_len += atom_len;
+        }
+    }
+
+    if !current.is_empty() {
+        parts.push(current);
+    }
+
+    parts
+}
+
+/**
+ * Split a message into parts of at most `max_len`, keeping lines together
+ * where possible.
+ */
+fn split_message(text: &str, max_len: usize, separator: &str, html: bool) -> Vec<String> {
+    let mut parts = vec![];
+    let mut current = String::new();
+
+    for line in text.split(separator) {
+        let needed = message_length(line)
+            + if current.is_empty() {
+                0
+            } else {
+                message_length(separator)
+            };
+
+        if message_length(&current) + needed <= max_len {
+            if !current.is_empty() {
+                current.push_str(separator);
+            }
+            current.push_str(line);
+            continue;
+        }
+
+        if !current.is_empty() {
+            parts.push(std::mem::take(&mut current));
+        }
+
+        if message_length(line) <= max_len {
+            current.push_str(line);
+        } else {
+            let mut line_parts = split_line(line, max_len, html);
+            current = line_parts.pop().unwrap_or_default();
+            parts.extend(line_parts);
+        }
+    }
+
+    if !current.is_empty() {
+        parts.push(current);
+    }
+
+    parts
+}
+
+/**
+ * Make a text message acceptable to the server: converted to plain text if
+ * HTML is not allowed, and split into several messages if it is too long.
+ * Lines are separated by `<br>` in HTML messages. Messages with an image in
+ * them can't be split, and are only checked against the image message limit
+ * by the server itself.
+ */
+pub fn prepare_message(text: &str, limits: &ServerLimits) -> Vec<String> {
+    if text.contains("<img") && limits.allow_html {
+        return vec![text.to_string()];
+    }
+
+    let (text, separator) = if limits.allow_html {
+        (text.to_string(), "<br>")
+    } else {
+        (html_to_plain(text), "\n")
+    };
+
+    match limits.message_length {
+        Some(max_len) => split_message(&text, max_len as usize, separator, limits.allow_html),
+        None => vec![text],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_lines_are_not_split_inside_tags_or_entities() {
+        let link = r#"<a href="https://example.com/a" title="x y">Tom&amp;Jerry</a>"#;
+        for max_len in 1..=message_length(link) {
+            let parts = split_line(link, max_len, true);
+            assert_eq!(parts.concat(), link);
+
+            for part in &parts {
+                assert_eq!(
+                    part.matches('<').count(),
+                    part.matches('>').count(),
+                    "{:?}",
+                    parts
+                );
+                assert_eq!(
+                    part.matches('&').count(),
+                    part.matches(';').count(),
+                    "{:?}",
+                    parts
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn plain_lines_are_split_anywhere() {
+        assert_eq!(split_line("a<b&c;", 2, false), ["a<", "b&", "c;"]);
+        assert_eq!(split_line("one two three", 7, false), ["one two", "three"]);
+    }
+}
@@ -0,0 +1,532 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use log::{error, info, warn};
+use sha2::{Digest, Sha256};
+use tokio_rustls::rustls::{
+    self,
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+
+use crate::types::Config;
+
+const DEFAULT_KNOWN_HOSTS_FILE: &str = "known_hosts";
+const DEFAULT_CERT_FILE: &str = "cert.pem";
+const DEFAULT_KEY_FILE: &str = "key.pem";
+
+/**
+ * SHA-256 fingerprint of a certificate, formatted like `openssl x509 -fingerprint`
+ * does: uppercase hex bytes separated by colons.
+ */
+pub fn fingerprint(cert: &CertificateDer) -> String {
+    Sha256::digest(cert.as_ref())
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/**
+ * Bring a user-supplied fingerprint into the format of `fingerprint`, so
+ * both `ab:cd:...` and `ABCD...` can be used in the config.
+ */
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    let hex: Vec<char> = fingerprint
+        .chars()
+        .filter(|c| c.is_ascii_hexdigit())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+
+    hex.chunks(2)
+        .map(|pair| pair.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/**
+ * The file of server fingerprints we have trusted on first use,
+ * with one `host:port fingerprint` entry per line.
+ */
+#[derive(Debug)]
+struct KnownHosts {
+    path: String,
+    host: String,
+}
+
+impl KnownHosts {
+    fn lookup(&self) -> std::io::Result<Option<String>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if let Some((host, fingerprint)) = line.trim().split_once(' ') {
+                if host == self.host {
+                    return Ok(Some(normalize_fingerprint(fingerprint)));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn record(&self, fingerprint: &str) -> std::io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+
+        writeln!(file, "{} {}", self.host, fingerprint)
+    }
+}
+
+#[derive(Debug)]
+enum Pin {
+    /// Only the usual CA-based verification.
+    None,
+    /// Accept exactly the certificate with this fingerprint.
+    Fingerprint(String),
+    /// Accept whatever certificate the server presented the first time.
+    TrustOnFirstUse(KnownHosts),
+}
+
+/**
+ * Server certificate verifier that accepts a pinned certificate even if it is
+ * self-signed, and otherwise falls back to verification against the trusted CAs.
+ */
+#[derive(Debug)]
+struct PinningVerifier {
+    webpki: Arc<WebPkiServerVerifier>,
+    pin: Pin,
+}
+
+impl PinningVerifier {
+    fn check_pin(&self, expected: &str, actual: &str) -> Result<ServerCertVerified, rustls::Error> {
+        if expected == actual {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            error!(
+                "Server certificate does not match the pinned fingerprint!\n  expected: {}\n  actual:   {}",
+                expected, actual
+            );
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let actual = fingerprint(end_entity);
+
+        match &self.pin {
+            Pin::None => {
+                let res = self.webpki.verify_server_cert(
+                    end_entity,
+                    intermediates,
+                    server_name,
+                    ocsp_response,
+                    now,
+                );
+
+                if let Err(e) = &res {
+                    warn!(
+                        "Server certificate (SHA-256 {}) failed verification: {}. \
+                        Pin its fingerprint or enable trust on first use to accept it.",
+                        actual, e
+                    );
+                }
+
+                res
+            }
+            Pin::Fingerprint(expected) => self.check_pin(expected, &actual),
+            Pin::TrustOnFirstUse(known_hosts) => {
+                let known = known_hosts
+                    .lookup()
+                    .map_err(|e| rustls::Error::General(format!("reading known hosts: {}", e)))?;
+
+                match known {
+                    Some(expected) => self.check_pin(&expected, &actual),
+                    None => {
+                        info!(
+                            "First connection to {}, trusting its certificate with SHA-256 {}",
+                            known_hosts.host, actual
+                        );
+                        known_hosts.record(&actual).map_err(|e| {
+                            rustls::Error::General(format!("writing known hosts: {}", e))
+                        })?;
+
+                        Ok(ServerCertVerified::assertion())
+                    }
+                }
+            }
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.webpki.supported_verify_schemes()
+    }
+}
+
+/**
+ * Write a file only we can read, as a private key should be.
+ */
+fn write_private(path: &str, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(path)?.write_all(contents)
+}
+
+/**
+ * Create a self-signed client certificate and key if neither file exists yet.
+ * The server identifies us by this certificate, so it is kept for later runs.
+ */
+fn generate_client_certificate(
+    cfg: &Config,
+    cert_file: &str,
+    key_file: &str,
+) -> anyhow::Result<()> {
+    match (Path::new(cert_file).exists(), Path::new(key_file).exists()) {
+        (true, true) => return Ok(()),
+        (false, false) => {}
+        (true, false) => anyhow::bail!(
+            "{} exists, but its key {} does not; remove it to generate a new certificate",
+            cert_file,
+            key_file
+        ),
+        (false, true) => anyhow::bail!(
+            "{} exists, but its certificate {} does not; remove it to generate a new certificate",
+            key_file,
+            cert_file
+        ),
+    }
+
+    let mut params = rcgen::CertificateParams::new(vec![])?;
+    params
+        .distinguished_name
+        .push(rcgen::DnType::CommonName, cfg.username.clone());
+
+    let key_pair = rcgen::KeyPair::generate()?;
+    let cert = params.self_signed(&key_pair)?;
+
+    // Both are written aside and only moved into place once complete, so a
+    // failure never leaves one without the other.
+    let key_tmp = format!("{}.tmp", key_file);
+    let cert_tmp = format!("{}.tmp", cert_file);
+
+    let written = write_private(&key_tmp, key_pair.serialize_pem().as_bytes())
+        .and_then(|()| std::fs::write(&cert_tmp, cert.pem()))
+        .and_then(|()| std::fs::rename(&cert_tmp, cert_file))
+        .and_then(|()| {
+            std::fs::rename(&key_tmp, key_file).inspect_err(|_| {
+                let _ = std::fs::remove_file(cert_file);
+            })
+        });
+
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&key_tmp);
+        let _ = std::fs::remove_file(&cert_tmp);
+        return Err(e.into());
+    }
+
+    info!(
+        "Generated a new client certificate in {} and {}",
+        cert_file, key_file
+    );
+
+    Ok(())
+}
+
+/**
+ * Load our client certificate chain and private key, from either a PKCS#12
+ * bundle or a pair of PEM files.
+ */
+fn client_identity(
+    cfg: &Config,
+) -> anyhow::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    if let Some(pkcs12_file) = &cfg.pkcs12_file {
+        let data = std::fs::read(pkcs12_file)?;
+        let password = cfg.pkcs12_password.as_deref().unwrap_or_default();
+        let keystore = p12_keystore::KeyStore::from_pkcs12(&data, password)?;
+
+        let Some((_, key_chain)) = keystore.private_key_chain() else {
+            anyhow::bail!("{} does not contain a private key", pkcs12_file);
+        };
+
+        let cert_chain = key_chain
+            .chain()
+            .iter()
+            .map(|cert| CertificateDer::from(cert.as_der().to_vec()))
+            .collect();
+        let key = PrivateKeyDer::try_from(key_chain.key().to_vec()).map_err(anyhow::Error::msg)?;
+
+        return Ok((cert_chain, key));
+    }
+
+    let cert_file = cfg.cert_file.as_deref().unwrap_or(DEFAULT_CERT_FILE);
+    let key_file = cfg.key_file.as_deref().unwrap_or(DEFAULT_KEY_FILE);
+
+    generate_client_certificate(cfg, cert_file, key_file)?;
+
+    let cert_chain = CertificateDer::pem_file_iter(cert_file)?.collect::<Result<_, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(key_file)?;
+
+    Ok((cert_chain, key))
+}
+
+/**
+ * Build the TLS configuration for connecting to the server described in the config.
+ */
+pub fn client_config(cfg: &Config) -> anyhow::Result<ClientConfig> {
+    let mut root_cert_store = RootCertStore::empty();
+    root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+    if let Some(ca_file) = &cfg.ca_file {
+        for cert in CertificateDer::pem_file_iter(ca_file)? {
+            root_cert_store.add(cert?)?;
+        }
+    }
+
+    let pin = if let Some(expected) = &cfg.server_fingerprint {
+        Pin::Fingerprint(normalize_fingerprint(expected))
+    } else if cfg.trust_on_first_use.unwrap_or(false) {
+        Pin::TrustOnFirstUse(KnownHosts {
+            path: cfg
+                .known_hosts_file
+                .clone()
+                .unwrap_or_else(|| DEFAULT_KNOWN_HOSTS_FILE.into()),
+            host: format!("{}:{}", cfg.host, cfg.port),
+        })
+    } else {
+        Pin::None
+    };
+
+    let verifier = PinningVerifier {
+        webpki: WebPkiServerVerifier::builder(Arc::new(root_cert_store)).build()?,
+        pin,
+    };
+
+    let (cert_chain, key_der) = client_identity(cfg)?;
+
+    let config = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_client_auth_cert(cert_chain, key_der)?;
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn known_hosts(dir: &tempfile::TempDir) -> KnownHosts {
+        KnownHosts {
+            path: dir.path().join("known_hosts").to_string_lossy().into(),
+            host: "mumble.example:64738".into(),
+        }
+    }
+
+    fn self_signed() -> CertificateDer<'static> {
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let cert = rcgen::CertificateParams::new(vec!["mumble.example".into()])
+            .unwrap()
+            .self_signed(&key_pair)
+            .unwrap();
+        cert.der().clone()
+    }
+
+    fn verifier(pin: Pin) -> PinningVerifier {
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+
+        let mut root_cert_store = RootCertStore::empty();
+        root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+        PinningVerifier {
+            webpki: WebPkiServerVerifier::builder(Arc::new(root_cert_store))
+                .build()
+                .unwrap(),
+            pin,
+        }
+    }
+
+    fn verify(verifier: &PinningVerifier, cert: &CertificateDer) -> bool {
+        let server_name = ServerName::try_from("mumble.example").unwrap();
+        verifier
+            .verify_server_cert(cert, &[], &server_name, &[], UnixTime::now())
+            .is_ok()
+    }
+
+    fn identity_files(dir: &tempfile::TempDir) -> (String, String) {
+        let path = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+        (path("cert.pem"), path("key.pem"))
+    }
+
+    fn config() -> Config {
+        serde_json::from_value(serde_json::json!({
+            "host": "mumble.example",
+            "port": 64738,
+            "username": "Mumblebot",
+            "rspotify_client_id": "",
+            "rspotify_client_secret": "",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn client_certificates_are_generated_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert_file, key_file) = identity_files(&dir);
+
+        generate_client_certificate(&config(), &cert_file, &key_file).unwrap();
+        let cert = std::fs::read(&cert_file).unwrap();
+        PrivateKeyDer::from_pem_file(&key_file).unwrap();
+
+        // Kept for later runs.
+        generate_client_certificate(&config(), &cert_file, &key_file).unwrap();
+        assert_eq!(std::fs::read(&cert_file).unwrap(), cert);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&key_file).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn half_a_certificate_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert_file, key_file) = identity_files(&dir);
+
+        std::fs::write(&key_file, "").unwrap();
+        assert!(generate_client_certificate(&config(), &cert_file, &key_file).is_err());
+
+        std::fs::remove_file(&key_file).unwrap();
+        std::fs::write(&cert_file, "").unwrap();
+        assert!(generate_client_certificate(&config(), &cert_file, &key_file).is_err());
+    }
+
+    #[test]
+    fn failed_generation_leaves_nothing_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, key_file) = identity_files(&dir);
+        let cert_file = dir.path().join("missing/cert.pem");
+        let cert_file = cert_file.to_string_lossy();
+
+        assert!(generate_client_certificate(&config(), &cert_file, &key_file).is_err());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn fingerprints_are_normalized() {
+        assert_eq!(normalize_fingerprint("ab:cd:ef"), "AB:CD:EF");
+        assert_eq!(normalize_fingerprint("ABCDEF"), "AB:CD:EF");
+        assert_eq!(normalize_fingerprint(" aB cD-eF "), "AB:CD:EF");
+
+        let cert = self_signed();
+        let compact = fingerprint(&cert).replace(':', "").to_lowercase();
+        assert_eq!(normalize_fingerprint(&compact), fingerprint(&cert));
+    }
+
+    #[test]
+    fn known_hosts_are_looked_up_by_host_and_port() {
+        let dir = tempfile::tempdir().unwrap();
+        let known_hosts = known_hosts(&dir);
+
+        // No file yet is no entry, not an error.
+        assert_eq!(known_hosts.lookup().unwrap(), None);
+
+        std::fs::write(
+            &known_hosts.path,
+            "mumble.example:1234 00:11\n\nmumble.example:64738 aabb\n",
+        )
+        .unwrap();
+        assert_eq!(known_hosts.lookup().unwrap().as_deref(), Some("AA:BB"));
+
+        let other = KnownHosts {
+            host: "other.example:64738".into(),
+            ..known_hosts
+        };
+        assert_eq!(other.lookup().unwrap(), None);
+    }
+
+    #[test]
+    fn recorded_hosts_are_found_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let known_hosts = known_hosts(&dir);
+
+        known_hosts.record("AA:BB").unwrap();
+        assert_eq!(known_hosts.lookup().unwrap().as_deref(), Some("AA:BB"));
+    }
+
+    #[test]
+    fn trust_on_first_use_pins_the_first_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let verifier = verifier(Pin::TrustOnFirstUse(known_hosts(&dir)));
+        let first = self_signed();
+
+        assert!(verify(&verifier, &first));
+        assert_eq!(
+            known_hosts(&dir).lookup().unwrap(),
+            Some(fingerprint(&first))
+        );
+
+        // From then on, only that certificate is accepted.
+        assert!(verify(&verifier, &first));
+        assert!(!verify(&verifier, &self_signed()));
+    }
+
+    #[test]
+    fn pinned_fingerprints_accept_only_their_certificate() {
+        let cert = self_signed();
+        let verifier = verifier(Pin::Fingerprint(fingerprint(&cert)));
+
+        assert!(verify(&verifier, &cert));
+        assert!(!verify(&verifier, &self_signed()));
+
+        // Without a pin, a self-signed certificate is refused.
+        assert!(!verify(&self::verifier(Pin::None), &cert));
+    }
+}
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use num_derive::FromPrimitive;
 use prost::Message;
 use tokio::sync::mpsc;
@@ -7,7 +9,7 @@ use tokio::{
 };
 use tokio_rustls::client::TlsStream;
 
-use crate::mumble_proto;
+use crate::{mumble_proto, state::ServerLimits};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -15,19 +17,199 @@ pub struct Config {
     pub host: String,
     pub port: u16,
     pub username: String,
+    /// Server password, if the server requires one.
+    pub password: Option<String>,
+    /// Access tokens, for channels whose ACL requires one.
+    pub tokens: Option<Vec<String>>,
+    /// Client certificate chain as PEM. Defaults to `cert.pem`.
+    pub cert_file: Option<String>,
+    /// Client private key as PEM (PKCS#8, or PKCS#1/SEC1). Defaults to `key.pem`.
+    pub key_file: Option<String>,
+    /// PKCS#12 bundle with the client certificate and key, as exported by the
+    /// Mumble client. Used instead of `cert_file` and `key_file` when set.
+    pub pkcs12_file: Option<String>,
+    pub pkcs12_password: Option<String>,
+    /// Register ourselves on the server after connecting, so our identity
+    /// and the ACLs granted to it are kept across restarts.
+    pub register_self: Option<bool>,
+    /// Certificate hashes of the users allowed to use admin commands.
+    pub admins: Option<Vec<String>>,
+    /// Channel to move to after connecting, as a path like `Music/Lounge`.
+    pub channel: Option<String>,
+    /// Shortest wait before reconnecting, in seconds. Defaults to 1.
+    pub reconnect_min_delay_secs: Option<u64>,
+    /// Longest wait between reconnection attempts, in seconds. Defaults to 60.
+    pub reconnect_max_delay_secs: Option<u64>,
+    /// PEM file with additional CA certificates to trust for the server.
+    pub ca_file: Option<String>,
+    /// SHA-256 fingerprint of the server certificate. If set, only that certificate is accepted.
+    pub server_fingerprint: Option<String>,
+    /// Trust the server certificate seen on the first connection, and refuse any other later.
+    pub trust_on_first_use: Option<bool>,
+    /// Where trusted server fingerprints are kept. Defaults to `known_hosts`.
+    pub known_hosts_file: Option<String>,
+    /// Image to use as our avatar while no song is playing. Without one, the avatar is cleared.
+    pub avatar_file: Option<String>,
+    /// Volume (0 to 1) to turn the music down to while someone in our channel talks.
+    /// Ducking is off unless this is set.
+    pub duck_level: Option<f64>,
+    /// Time to fade the music down when someone starts talking, in milliseconds. Defaults to 100.
+    pub duck_attack_ms: Option<u64>,
+    /// Time to fade the music back up after everyone stopped, in milliseconds. Defaults to 800.
+    pub duck_release_ms: Option<u64>,
+    /// Directory `.record` writes recordings into, one subdirectory each. Defaults to `recordings`.
+    pub recording_dir: Option<String>,
+    /// Connections bridging our channel with channels elsewhere.
+    pub relays: Option<Vec<RelayConfig>>,
+    /// How the audio we send is encoded.
+    pub encoder: Option<EncoderConfig>,
+    /// How much of a song is buffered ahead of sending it.
+    pub buffer: Option<BufferConfig>,
+    /// Milliseconds over which one song fades into the next.
+    pub crossfade_ms: Option<u64>,
+    /// Join songs back to back, without a gap; wins over `crossfade_ms`.
+    pub gapless: Option<bool>,
     pub rspotify_client_id: String,
     pub rspotify_client_secret: String,
 }
 
+/**
+ * Opus encoder settings for the audio we send.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EncoderConfig {
+    /// Bits per second. Lowered as needed to stay within the server's bandwidth
+    /// limit; without one, the encoder uses whatever that limit allows.
+    pub bitrate: Option<u32>,
+    /// Defaults to `vbr`.
+    pub bitrate_mode: Option<BitrateMode>,
+    /// From 0 to 10, trading CPU time for quality. Defaults to libopus' choice.
+    pub complexity: Option<u8>,
+    /// In-band forward error correction, so listeners can recover lost packets.
+    pub fec: Option<bool>,
+    /// Packet loss to prepare for, in percent. Defaults to 15.
+    pub packet_loss_perc: Option<u8>,
+    /// Send mono instead of stereo.
+    pub mono: Option<bool>,
+    /// Audio in each packet, in milliseconds: 10, 20, 40 or 60. Defaults to 10.
+    pub frame_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BitrateMode {
+    /// Variable bitrate.
+    Vbr,
+    /// Constrained variable bitrate, which stays close to the set bitrate.
+    Cvbr,
+    /// Constant bitrate.
+    Cbr,
+}
+
+/**
+ * Buffering of the audio we send, in milliseconds of audio.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BufferConfig {
+    /// Buffered before a song starts playing. Defaults to 200.
+    pub start_ms: Option<u64>,
+    /// Most to read ahead of what is playing. Defaults to 30000.
+    pub high_water_ms: Option<u64>,
+    /// Buffered again before playing on after running dry mid-song. Defaults to 1000.
+    pub underrun_ms: Option<u64>,
+}
+
+/**
+ * A second connection that passes voice (and optionally chat) between our
+ * channel and a channel on another server, or another channel on ours.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RelayConfig {
+    /// Server to connect to. Defaults to ours.
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub username: String,
+    pub password: Option<String>,
+    /// Channel to relay to and from, as a path like `Music/Lounge`.
+    pub channel: String,
+    /// Client certificate and key as PEM. Default to ours.
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+    /// Pass text messages between the two channels too. Off by default.
+    pub relay_chat: Option<bool>,
+}
+
+/**
+ * Recipient of a text message sent by the bot.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextTarget {
+    Channel(u32),
+    User(u32),
+    Tree(u32),
+}
+
+impl TextTarget {
+    /**
+     * Where to answer a received text message: privately if it was sent to us
+     * privately, otherwise to the channel (or channel tree) it was sent to.
+     */
+    pub fn reply_to(msg: &mumble_proto::TextMessage) -> Self {
+        match (msg.actor, msg.channel_id.first(), msg.tree_id.first()) {
+            (Some(actor), _, _) if !msg.session.is_empty() => TextTarget::User(actor),
+            (_, Some(&channel_id), _) => TextTarget::Channel(channel_id),
+            (_, None, Some(&tree_id)) => TextTarget::Tree(tree_id),
+            (Some(actor), None, None) => TextTarget::User(actor),
+            (None, None, None) => TextTarget::Channel(0),
+        }
+    }
+}
+
+/**
+ * One recipient of a whisper or shout, registered with a VoiceTarget message.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VoiceRecipient {
+    User(u32),
+    Channel {
+        channel_id: u32,
+        /// Include channels linked to this one.
+        links: bool,
+        /// Include all sub-channels.
+        children: bool,
+        /// Only users in this ACL group.
+        group: Option<String>,
+    },
+}
+
 #[derive(Debug, Clone)]
 pub enum PlayerAction {
-    PlaySong(Song),
+    PlaySong(Song, Request),
     Stop,
     Pause,
     Resume,
     Next,
-    ShowQueue,
+    /// Show a page of the queue, counting from 1.
+    ShowQueue(TextTarget, usize),
+    /// Show how full the audio buffer is.
+    ShowBuffer(TextTarget),
     SetVolume(f64),
+    /// The server's limits changed, or we (re)connected.
+    SetLimits(ServerLimits),
+    /// The connection dropped, hold the current song until we are back.
+    Suspend,
+    /// Reconnected, continue a suspended song.
+    Restore,
+}
+
+/**
+ * Who asked for a song, and where to tell them about it.
+ */
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub reply_to: TextTarget,
+    /// Name of the user who asked, if known.
+    pub requester: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -41,6 +223,9 @@ pub struct Song {
     pub name: String,
     pub id: String,
     pub song_type: SongType,
+    pub duration: Option<Duration>,
+    /// Cover art or thumbnail, used as our avatar while the song plays.
+    pub image_url: Option<String>,
 }
 
 #[repr(u16)]
@@ -72,6 +257,7 @@ pub enum MumbleType {
     RequestBlob,
     ServerConfig,
     SuggestConfig,
+    PluginDataTransmission,
 }
 
 /**
@@ -141,6 +327,55 @@ pub fn varint_encode(val: u64) -> Vec<u8> {
     v
 }
 
+/**
+Decode a Mumble varint from the start of a buffer.
+Returns the decoded value and the number of bytes it took up,
+or None if the buffer ends before the varint does.
+ */
+pub fn varint_decode(buf: &[u8]) -> Option<(u64, usize)> {
+    let first = *buf.first()? as u64;
+
+    // Big-endian value of the `len` bytes following the prefix byte.
+    let tail = |len: usize| -> Option<u64> {
+        let bytes = buf.get(1..1 + len)?;
+        Some(bytes.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64))
+    };
+
+    if first & 0x80 == 0x00 {
+        // 7-bit positive number
+        Some((first & 0x7F, 1))
+    } else if first & 0xC0 == 0x80 {
+        // 14-bit positive number
+        Some((((first & 0x3F) << 8) | tail(1)?, 2))
+    } else if first & 0xE0 == 0xC0 {
+        // 21-bit positive number
+        Some((((first & 0x1F) << 16) | tail(2)?, 3))
+    } else if first & 0xF0 == 0xE0 {
+        // 28-bit positive number
+        Some((((first & 0x0F) << 24) | tail(3)?, 4))
+    } else {
+        match first & 0xFC {
+            // 32-bit positive number
+            0xF0 => Some((tail(4)?, 5)),
+            // 64-bit number
+            0xF4 => Some((tail(8)?, 9)),
+            // Negative number, followed by the varint encoding of its inverse.
+            // The encoder never nests these, and refusing to keeps a run of 0xF8
+            // bytes from recursing once per byte.
+            0xF8 => {
+                let rest = &buf[1..];
+                if rest.first().is_some_and(|&b| b & 0xF8 == 0xF8) {
+                    return None;
+                }
+                let (v, len) = varint_decode(rest)?;
+                Some((!v, len + 1))
+            }
+            // Shortcase for -1 to -4
+            _ => Some((!(first & 0x03), 1)),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum MumbleMsg {
     Version(mumble_proto::Version),
@@ -169,6 +404,7 @@ pub enum MumbleMsg {
     RequestBlob(mumble_proto::RequestBlob),
     ServerConfig(mumble_proto::ServerConfig),
     SuggestConfig(mumble_proto::SuggestConfig),
+    PluginDataTransmission(mumble_proto::PluginDataTransmission),
 }
 
 impl MumbleMsg {
@@ -200,13 +436,14 @@ impl MumbleMsg {
             MumbleMsg::RequestBlob(_) => MumbleType::RequestBlob,
             MumbleMsg::ServerConfig(_) => MumbleType::ServerConfig,
             MumbleMsg::SuggestConfig(_) => MumbleType::SuggestConfig,
+            MumbleMsg::PluginDataTransmission(_) => MumbleType::PluginDataTransmission,
         }
     }
 
     pub fn as_data(&self) -> Vec<u8> {
         match self {
             MumbleMsg::Version(version) => version.encode_to_vec(),
-            MumbleMsg::UDPTunnel(_) => unimplemented!(),
+            MumbleMsg::UDPTunnel(packet) => packet.clone(),
             MumbleMsg::Authenticate(authenticate) => authenticate.encode_to_vec(),
             MumbleMsg::Ping(ping) => ping.encode_to_vec(),
             MumbleMsg::Reject(reject) => reject.encode_to_vec(),
@@ -233,13 +470,16 @@ impl MumbleMsg {
             MumbleMsg::RequestBlob(request_blob) => request_blob.encode_to_vec(),
             MumbleMsg::ServerConfig(server_config) => server_config.encode_to_vec(),
             MumbleMsg::SuggestConfig(suggest_config) => suggest_config.encode_to_vec(),
+            MumbleMsg::PluginDataTransmission(plugin_data_transmission) => {
+                plugin_data_transmission.encode_to_vec()
+            }
         }
     }
 
     pub fn from_tagged_data(tag: MumbleType, buf: &[u8]) -> anyhow::Result<Self> {
         let msg = match tag {
             MumbleType::Version => MumbleMsg::Version(mumble_proto::Version::decode(buf)?),
-            MumbleType::UDPTunnel => unimplemented!(),
+            MumbleType::UDPTunnel => MumbleMsg::UDPTunnel(buf.to_vec()),
             MumbleType::Authenticate => {
                 MumbleMsg::Authenticate(mumble_proto::Authenticate::decode(buf)?)
             }
@@ -290,6 +530,9 @@ impl MumbleMsg {
             MumbleType::SuggestConfig => {
                 MumbleMsg::SuggestConfig(mumble_proto::SuggestConfig::decode(buf)?)
             }
+            MumbleType::PluginDataTransmission => MumbleMsg::PluginDataTransmission(
+                mumble_proto::PluginDataTransmission::decode(buf)?,
+            ),
         };
 
         Ok(msg)
@@ -301,3 +544,110 @@ pub type ReadStream = ReadHalf<TlsStream<TcpStream>>;
 
 pub type MumbleMsgSink = mpsc::Sender<MumbleMsg>;
 pub type MumbleMsgSource = mpsc::Receiver<MumbleMsg>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Encodings taken from Mumble's PacketDataStream, one per prefix form.
+    const VARINT_FIXTURES: &[(u64, &[u8])] = &[
+        (0, &[0x00]),
+        (0x7F, &[0x7F]),
+        (0x80, &[0x80, 0x80]),
+        (0x3FFF, &[0xBF, 0xFF]),
+        (0x4000, &[0xC0, 0x40, 0x00]),
+        (0x1F_FFFF, &[0xDF, 0xFF, 0xFF]),
+        (0x20_0000, &[0xE0, 0x20, 0x00, 0x00]),
+        (0x0FFF_FFFF, &[0xEF, 0xFF, 0xFF, 0xFF]),
+        (0x1000_0000, &[0xF0, 0x10, 0x00, 0x00, 0x00]),
+        (0xFFFF_FFFF, &[0xF0, 0xFF, 0xFF, 0xFF, 0xFF]),
+        (
+            0x1_0000_0000,
+            &[0xF4, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00],
+        ),
+        (
+            0x8000_0000_0000_0000,
+            &[0xF4, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        ),
+        (-1i64 as u64, &[0xFC]),
+        (-4i64 as u64, &[0xFF]),
+        (-5i64 as u64, &[0xF8, 0x04]),
+        (-0x81i64 as u64, &[0xF8, 0x80, 0x80]),
+        (
+            i32::MIN as i64 as u64,
+            &[0xF8, 0xF0, 0x7F, 0xFF, 0xFF, 0xFF],
+        ),
+        (
+            -0x1_0000_0001i64 as u64,
+            &[0xF4, 0xFF, 0xFF, 0xFF, 0xFE, 0xFF, 0xFF, 0xFF, 0xFF],
+        ),
+    ];
+
+    #[test]
+    fn varint_fixtures_encode() {
+        for &(value, bytes) in VARINT_FIXTURES {
+            assert_eq!(varint_encode(value), bytes, "encoding {:#x}", value);
+        }
+    }
+
+    #[test]
+    fn varint_fixtures_decode() {
+        for &(value, bytes) in VARINT_FIXTURES {
+            assert_eq!(
+                varint_decode(bytes),
+                Some((value, bytes.len())),
+                "decoding {:02x?}",
+                bytes
+            );
+        }
+    }
+
+    #[test]
+    fn varint_truncated_is_none() {
+        for &(_, bytes) in VARINT_FIXTURES {
+            for len in 0..bytes.len() {
+                assert_eq!(
+                    varint_decode(&bytes[..len]),
+                    None,
+                    "decoding {:02x?}",
+                    &bytes[..len]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn varint_nested_negative_is_none() {
+        assert_eq!(varint_decode(&[0xF8, 0xFC]), None);
+        assert_eq!(varint_decode(&[0xF8; 4096]), None);
+    }
+
+    proptest! {
+        #[test]
+        fn varint_round_trips(value: u64) {
+            let encoded = varint_encode(value);
+            prop_assert_eq!(varint_decode(&encoded), Some((value, encoded.len())));
+        }
+
+        #[test]
+        fn varint_round_trips_negative(value in i64::MIN..0) {
+            let encoded = varint_encode(value as u64);
+            prop_assert_eq!(varint_decode(&encoded), Some((value as u64, encoded.len())));
+        }
+
+        #[test]
+        fn varint_ignores_trailing_data(value: u64, trailing: Vec<u8>) {
+            let encoded = varint_encode(value);
+            let buf = [encoded.as_slice(), &trailing].concat();
+            prop_assert_eq!(varint_decode(&buf), Some((value, encoded.len())));
+        }
+
+        #[test]
+        fn varint_decode_stays_in_bounds(buf: Vec<u8>) {
+            if let Some((_, len)) = varint_decode(&buf) {
+                prop_assert!(len >= 1 && len <= buf.len());
+            }
+        }
+    }
+}
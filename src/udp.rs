@@ -0,0 +1,272 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, trace, warn};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, Mutex},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    crypt::CryptState,
+    mumble_proto,
+    types::{MumbleMsg, MumbleMsgSink},
+    voice::{self, VoiceFormat},
+};
+
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// If no UDP ping reply has been received for this long, voice goes through the TCP tunnel.
+const UDP_TIMEOUT: Duration = Duration::from_secs(12);
+
+struct UdpState {
+    crypt: CryptState,
+    format: VoiceFormat,
+    last_pong: Option<Instant>,
+    active: bool,
+}
+
+/**
+ * The encrypted UDP voice channel to the server.
+ * Keys are taken from the CryptSetup message; until the server answers our
+ * UDP pings, voice keeps flowing through the TCP tunnel.
+ */
+pub struct UdpTransport {
+    /// None when no socket could be bound, which leaves only the TCP tunnel.
+    socket: Option<UdpSocket>,
+    state: Mutex<UdpState>,
+    control: MumbleMsgSink,
+    epoch: Instant,
+}
+
+impl UdpTransport {
+    /**
+     * Bind a socket for voice to `server`. If that fails, voice keeps going
+     * through the TCP tunnel.
+     */
+    pub async fn bind(server: SocketAddr, control: MumbleMsgSink) -> Self {
+        let socket = match Self::bind_socket(server).await {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                warn!("Could not bind a UDP socket, using the TCP tunnel: {:?}", e);
+                None
+            }
+        };
+
+        UdpTransport {
+            socket,
+            state: Mutex::new(UdpState {
+                crypt: CryptState::new(),
+                format: VoiceFormat::Legacy,
+                last_pong: None,
+                active: false,
+            }),
+            control,
+            epoch: Instant::now(),
+        }
+    }
+
+    async fn bind_socket(server: SocketAddr) -> anyhow::Result<UdpSocket> {
+        let local: SocketAddr = if server.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(server).await?;
+
+        Ok(socket)
+    }
+
+    pub async fn handle_crypt_setup(&self, msg: &mumble_proto::CryptSetup) -> anyhow::Result<()> {
+        let mut state = self.state.lock().await;
+
+        match (&msg.key, &msg.client_nonce, &msg.server_nonce) {
+            (Some(key), Some(client_nonce), Some(server_nonce)) => {
+                debug!("Received UDP crypt key from server.");
+                state.crypt.set_key(key, client_nonce, server_nonce)?;
+                state.last_pong = None;
+            }
+            (_, _, Some(server_nonce)) => {
+                debug!("Resynchronized UDP decrypt nonce.");
+                state.crypt.set_decrypt_iv(server_nonce)?;
+            }
+            _ => {
+                // The server wants our nonce.
+                let reply = mumble_proto::CryptSetup {
+                    client_nonce: Some(state.crypt.encrypt_iv().to_vec()),
+                    ..Default::default()
+                };
+                drop(state);
+
+                self.control.send(MumbleMsg::CryptSetup(reply)).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn set_format(&self, format: VoiceFormat) {
+        debug!("Using {:?} voice packet format.", format);
+        self.state.lock().await.format = format;
+    }
+
+    /**
+     * The packet format negotiated with the server, used both over UDP
+     * and through the TCP tunnel.
+     */
+    pub async fn format(&self) -> VoiceFormat {
+        self.state.lock().await.format
+    }
+
+    /**
+     * Whether voice should currently be sent over UDP.
+     */
+    pub async fn is_active(&self) -> bool {
+        let mut state = self.state.lock().await;
+
+        let active = state.crypt.is_valid()
+            && state
+                .last_pong
+                .is_some_and(|last_pong| last_pong.elapsed() < UDP_TIMEOUT);
+
+        if active != state.active {
+            if active {
+                info!("UDP voice channel established.");
+            } else {
+                info!("No UDP connectivity, falling back to the TCP tunnel.");
+            }
+            state.active = active;
+        }
+
+        active
+    }
+
+    pub async fn send(&self, packet: &[u8]) -> anyhow::Result<()> {
+        let Some(socket) = &self.socket else {
+            anyhow::bail!("no UDP socket");
+        };
+
+        let encrypted = {
+            let mut state = self.state.lock().await;
+            if !state.crypt.is_valid() {
+                anyhow::bail!("UDP crypt state not set up yet");
+            }
+            state.crypt.encrypt(packet)
+        };
+
+        socket.send(&encrypted).await?;
+
+        Ok(())
+    }
+
+    /**
+     * Build a TCP ping carrying our UDP packet statistics.
+     */
+    pub async fn tcp_ping(&self) -> mumble_proto::Ping {
+        let state = self.state.lock().await;
+
+        mumble_proto::Ping {
+            timestamp: Some(self.epoch.elapsed().as_micros() as u64),
+            good: Some(state.crypt.good),
+            late: Some(state.crypt.late),
+            lost: Some(state.crypt.lost),
+            resync: Some(state.crypt.resync),
+            ..Default::default()
+        }
+    }
+
+    async fn send_ping(&self) -> anyhow::Result<()> {
+        let format = {
+            let state = self.state.lock().await;
+            if !state.crypt.is_valid() {
+                return Ok(());
+            }
+            state.format
+        };
+
+        let packet = voice::encode_ping(format, self.epoch.elapsed().as_micros() as u64);
+
+        self.send(&packet).await
+    }
+
+    /**
+     * Decrypt an incoming packet, returning it if it carries voice data.
+     */
+    async fn handle_packet(&self, encrypted: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut state = self.state.lock().await;
+
+        let Some(plain) = state.crypt.decrypt(encrypted) else {
+            trace!("Failed to decrypt UDP packet of length {}", encrypted.len());
+
+            if state.crypt.should_request_resync() {
+                debug!("Requesting UDP crypt resync.");
+                drop(state);
+                self.control
+                    .send(MumbleMsg::CryptSetup(mumble_proto::CryptSetup::default()))
+                    .await?;
+            }
+
+            return Ok(None);
+        };
+
+        if plain.is_empty() {
+            Ok(None)
+        } else if voice::is_ping(&plain) {
+            state.last_pong = Some(Instant::now());
+            Ok(None)
+        } else {
+            trace!(target: "mumblebot::net::voice", "Received UDP voice packet, length {}", plain.len());
+            Ok(Some(plain))
+        }
+    }
+}
+
+/**
+ * Task that pings the server over UDP and forwards received voice packets
+ * into the same channel as the TCP receiver task.
+ */
+pub async fn udp_task(
+    udp: Arc<UdpTransport>,
+    channel: mpsc::Sender<MumbleMsg>,
+    ct: CancellationToken,
+) {
+    let Some(socket) = &udp.socket else {
+        return;
+    };
+
+    let mut ping_interval = tokio::time::interval(PING_INTERVAL);
+    let mut buf = vec![0u8; 2048];
+
+    loop {
+        let res = tokio::select! {
+            _ = ping_interval.tick() => {
+                udp.send_ping().await.map(|_| None)
+            }
+            res = socket.recv(&mut buf) => {
+                match res {
+                    Ok(len) => udp.handle_packet(&buf[..len]).await,
+                    Err(e) => Err(e.into()),
+                }
+            }
+            _ = ct.cancelled() => {
+                break
+            }
+        };
+
+        match res {
+            Ok(Some(packet)) => {
+                if channel.send(MumbleMsg::UDPTunnel(packet)).await.is_err() {
+                    break;
+                }
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Error on UDP voice channel: {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,597 @@
+use std::{
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::{debug, trace};
+use opus::{Channels, Decoder};
+use prost::Message;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::{mumble_proto, mumble_udp, types};
+
+const SAMPLE_RATE: u32 = 48_000;
+
+/// Samples per channel in one 10 ms frame; Mumble sequence numbers count these.
+const SAMPLES_PER_SEQ: usize = SAMPLE_RATE as usize / 100;
+
+/// Largest Opus packet duration (120 ms), in interleaved stereo samples.
+const MAX_DECODED_SAMPLES: usize = SAMPLES_PER_SEQ * 12 * 2;
+
+/// How long a packet is held back to wait for earlier, reordered packets.
+const JITTER_DELAY: Duration = Duration::from_millis(40);
+
+/// Packets are released early once this many are buffered for one user.
+const JITTER_MAX_PACKETS: usize = 5;
+
+/// Gaps longer than this (in 10 ms frames) are not concealed.
+const MAX_CONCEALED_FRAMES: u64 = 6;
+
+/// A sequence number this far behind the expected one means the sender restarted.
+const SEQ_RESET_THRESHOLD: u64 = 100;
+
+/// Decoder state is dropped for users that have not sent voice for this long.
+const USER_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Someone counts as talking until we have not heard from them for this long.
+const TALKING_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Voice target for talking normally to our own channel.
+pub const NORMAL_TALKING: u8 = 0;
+
+/// Legacy UDP packet types, in the upper three bits of the header byte.
+const UDP_TYPE_PING: u8 = 1;
+const UDP_TYPE_OPUS: u8 = 4;
+
+/// Header bytes of the protobuf UDP packets introduced in Mumble 1.5.
+const PROTOBUF_TYPE_AUDIO: u8 = 0;
+const PROTOBUF_TYPE_PING: u8 = 1;
+
+/// First server version to speak the protobuf UDP format.
+const PROTOBUF_MIN_VERSION_V2: u64 = 0x0001_0005_0000_0000;
+const PROTOBUF_MIN_VERSION_V1: u32 = 0x0001_0500;
+
+/**
+ * Wire format of the packets sent over UDP and through the TCP tunnel.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoiceFormat {
+    Legacy,
+    Protobuf,
+}
+
+impl VoiceFormat {
+    /**
+     * Pick the format to send in, based on the server's Version message.
+     */
+    pub fn for_server(version: &mumble_proto::Version) -> Self {
+        let is_new = match (version.version_v2, version.version_v1) {
+            (Some(v2), _) => v2 >= PROTOBUF_MIN_VERSION_V2,
+            (None, Some(v1)) => v1 >= PROTOBUF_MIN_VERSION_V1,
+            (None, None) => false,
+        };
+
+        if is_new {
+            VoiceFormat::Protobuf
+        } else {
+            VoiceFormat::Legacy
+        }
+    }
+}
+
+/**
+ * Build a voice packet carrying one Opus frame to the given voice target.
+ * A terminator packet ends the transmission.
+ */
+pub fn encode_voice_packet(
+    format: VoiceFormat,
+    target: u8,
+    seq_nr: u64,
+    data: &[u8],
+    terminator: bool,
+) -> Vec<u8> {
+    match format {
+        VoiceFormat::Legacy => {
+            let opus_header = data.len() as u64 | if terminator { 0x2000 } else { 0 };
+            let seq_nr_encoded = types::varint_encode(seq_nr);
+            let len_encoded = types::varint_encode(opus_header);
+
+            let mut packet =
+                Vec::with_capacity(1 + seq_nr_encoded.len() + len_encoded.len() + data.len());
+
+            packet.push((UDP_TYPE_OPUS << 5) | (target & 0x1F));
+            packet.extend(seq_nr_encoded);
+            packet.extend(len_encoded);
+            packet.extend_from_slice(data);
+
+            packet
+        }
+        VoiceFormat::Protobuf => {
+            let audio = mumble_udp::Audio {
+                header: Some(mumble_udp::audio::Header::Target(target as u32)),
+                frame_number: seq_nr,
+                opus_data: data.to_vec(),
+                is_terminator: terminator,
+                ..Default::default()
+            };
+
+            let mut packet = vec![PROTOBUF_TYPE_AUDIO];
+            packet.extend(audio.encode_to_vec());
+
+            packet
+        }
+    }
+}
+
+/**
+ * Build a UDP ping packet carrying the given timestamp.
+ */
+pub fn encode_ping(format: VoiceFormat, timestamp: u64) -> Vec<u8> {
+    match format {
+        VoiceFormat::Legacy => {
+            let mut packet = vec![UDP_TYPE_PING << 5];
+            packet.extend(types::varint_encode(timestamp));
+            packet
+        }
+        VoiceFormat::Protobuf => {
+            let ping = mumble_udp::Ping {
+                timestamp,
+                ..Default::default()
+            };
+
+            let mut packet = vec![PROTOBUF_TYPE_PING];
+            packet.extend(ping.encode_to_vec());
+            packet
+        }
+    }
+}
+
+/**
+ * Whether a decrypted UDP packet is a ping (reply), in either format.
+ * A legacy header byte of 0 or 1 would be a CELT packet, which Mumble no
+ * longer sends, so the two formats can be told apart by the first byte.
+ */
+pub fn is_ping(packet: &[u8]) -> bool {
+    match packet.first() {
+        Some(&PROTOBUF_TYPE_PING) => true,
+        Some(header) => header >> 5 == UDP_TYPE_PING,
+        None => false,
+    }
+}
+
+/**
+ * How far an Opus packet moves the sequence number on: one per 10 ms of audio.
+ */
+pub fn seq_frames(opus_data: &[u8]) -> u64 {
+    opus::packet::get_nb_samples(opus_data, SAMPLE_RATE)
+        .map_or(1, |samples| (samples / SAMPLES_PER_SEQ).max(1) as u64)
+}
+
+/**
+ * A voice packet as received from the server, either over UDP or through the TCP tunnel.
+ */
+#[derive(Debug, Clone)]
+pub struct VoicePacket {
+    pub target: u8,
+    pub session: u32,
+    pub seq_nr: u64,
+    pub payload: Vec<u8>,
+    pub terminator: bool,
+    pub position: Option<[f32; 3]>,
+}
+
+impl VoicePacket {
+    /**
+     * Parse a server-to-client voice packet, in either the legacy or the
+     * protobuf format.
+     */
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        let Some((&header, rest)) = data.split_first() else {
+            anyhow::bail!("empty voice packet");
+        };
+
+        if header == PROTOBUF_TYPE_AUDIO {
+            Self::parse_protobuf(rest)
+        } else {
+            Self::parse_legacy(header, rest)
+        }
+    }
+
+    fn parse_protobuf(data: &[u8]) -> anyhow::Result<Self> {
+        let audio = mumble_udp::Audio::decode(data)?;
+
+        let target = match audio.header {
+            Some(mumble_udp::audio::Header::Context(context)) => context as u8,
+            Some(mumble_udp::audio::Header::Target(target)) => target as u8,
+            None => 0,
+        };
+
+        let position = <[f32; 3]>::try_from(audio.positional_data.as_slice()).ok();
+
+        Ok(VoicePacket {
+            target,
+            session: audio.sender_session,
+            seq_nr: audio.frame_number,
+            payload: audio.opus_data,
+            terminator: audio.is_terminator,
+            position,
+        })
+    }
+
+    fn parse_legacy(header: u8, mut rest: &[u8]) -> anyhow::Result<Self> {
+        let packet_type = header >> 5;
+        let target = header & 0x1F;
+
+        if packet_type != UDP_TYPE_OPUS {
+            anyhow::bail!("unsupported voice packet type {}", packet_type);
+        }
+
+        let mut next_varint = |what: &str| -> anyhow::Result<u64> {
+            let (value, len) = types::varint_decode(rest)
+                .ok_or_else(|| anyhow::anyhow!("truncated {} in voice packet", what))?;
+            rest = &rest[len..];
+            Ok(value)
+        };
+
+        let session = next_varint("session")? as u32;
+        let seq_nr = next_varint("sequence number")?;
+        let opus_header = next_varint("Opus header")?;
+
+        let payload_len = (opus_header & 0x1FFF) as usize;
+        let terminator = opus_header & 0x2000 != 0;
+
+        if rest.len() < payload_len {
+            anyhow::bail!("truncated Opus payload in voice packet");
+        }
+
+        let (payload, rest) = rest.split_at(payload_len);
+
+        // Positional audio is three floats in the sender's native (little-endian) byte order.
+        let position = (rest.len() >= 12).then(|| {
+            let float = |i: usize| f32::from_le_bytes(rest[i * 4..i * 4 + 4].try_into().unwrap());
+            [float(0), float(1), float(2)]
+        });
+
+        Ok(VoicePacket {
+            target,
+            session,
+            seq_nr,
+            payload: payload.to_vec(),
+            terminator,
+            position,
+        })
+    }
+}
+
+/**
+ * A chunk of decoded 48 kHz interleaved stereo PCM from a single user.
+ */
+#[derive(Debug, Clone)]
+pub struct VoiceFrame {
+    pub session: u32,
+    pub pcm: Arc<[i16]>,
+}
+
+pub type VoiceFrameSink = broadcast::Sender<VoiceFrame>;
+
+/**
+ * Who is talking, going by the headers of the voice packets we receive. A
+ * transmission ends with its terminator packet, or when the user goes quiet
+ * without one. Packets are looked at as they arrive, so ducking doesn't wait
+ * for them to be decoded, and nothing is decoded just for this.
+ */
+#[derive(Debug, Default)]
+pub struct Speakers {
+    last_heard: HashMap<u32, Instant>,
+}
+
+impl Speakers {
+    pub fn heard(&mut self, packet: &VoicePacket) {
+        if packet.terminator {
+            self.last_heard.remove(&packet.session);
+        } else {
+            self.last_heard.insert(packet.session, Instant::now());
+        }
+    }
+
+    /**
+     * Whether any of the users accepted by `filter` is talking.
+     */
+    pub fn anyone_talking(&mut self, filter: impl Fn(u32) -> bool) -> bool {
+        self.last_heard
+            .retain(|_, last_heard| last_heard.elapsed() < TALKING_TIMEOUT);
+
+        self.last_heard.keys().any(|&session| filter(session))
+    }
+}
+
+/**
+ * Per-user decoder and jitter buffer.
+ */
+struct UserStream {
+    session: u32,
+    decoder: Decoder,
+    jitter: BTreeMap<u64, (Instant, VoicePacket)>,
+    next_seq: Option<u64>,
+    flush: bool,
+    last_activity: Instant,
+}
+
+impl UserStream {
+    fn new(session: u32) -> anyhow::Result<Self> {
+        Ok(UserStream {
+            session,
+            decoder: Decoder::new(SAMPLE_RATE, Channels::Stereo)?,
+            jitter: BTreeMap::new(),
+            next_seq: None,
+            flush: false,
+            last_activity: Instant::now(),
+        })
+    }
+
+    fn push(&mut self, packet: VoicePacket) {
+        if let Some(next) = self.next_seq {
+            if packet.seq_nr.saturating_add(SEQ_RESET_THRESHOLD) < next {
+                debug!("Voice sequence of session {} restarted.", self.session);
+                self.next_seq = None;
+            } else if packet.seq_nr < next {
+                trace!("Dropping late voice packet from session {}", self.session);
+                return;
+            }
+        }
+
+        self.flush |= packet.terminator;
+        self.last_activity = Instant::now();
+        self.jitter.insert(packet.seq_nr, (Instant::now(), packet));
+    }
+
+    /**
+     * Decode every packet that has waited long enough in the jitter buffer,
+     * concealing short gaps in the sequence.
+     */
+    fn release(&mut self) -> anyhow::Result<Vec<VoiceFrame>> {
+        let mut frames = vec![];
+        let mut pcm = vec![0i16; MAX_DECODED_SAMPLES];
+
+        while let Some((_, (arrival, _))) = self.jitter.first_key_value() {
+            let ready = self.flush
+                || self.jitter.len() >= JITTER_MAX_PACKETS
+                || arrival.elapsed() >= JITTER_DELAY;
+
+            if !ready {
+                break;
+            }
+
+            let (seq_nr, (_, packet)) = self.jitter.pop_first().unwrap();
+
+            if let Some(next) = self.next_seq {
+                let missing = seq_nr.saturating_sub(next);
+                if missing > 0 && missing <= MAX_CONCEALED_FRAMES {
+                    let len = self.decoder.decode(
+                        &[],
+                        &mut pcm[..missing as usize * SAMPLES_PER_SEQ * 2],
+                        false,
+                    )?;
+                    frames.push(VoiceFrame {
+                        session: self.session,
+                        pcm: pcm[..len * 2].into(),
+                    });
+                }
+            }
+
+            let len = self.decoder.decode(&packet.payload, &mut pcm, false)?;
+
+            frames.push(VoiceFrame {
+                session: self.session,
+                pcm: pcm[..len * 2].into(),
+            });
+
+            // Sequence numbers come from the client, so they may be anything.
+            self.next_seq = Some(seq_nr.saturating_add((len / SAMPLES_PER_SEQ).max(1) as u64));
+        }
+
+        if self.jitter.is_empty() {
+            self.flush = false;
+        }
+
+        Ok(frames)
+    }
+}
+
+/**
+ * Task that parses incoming voice packets and publishes the decoded audio of
+ * every user on `frames`, for anything that wants to listen along.
+ */
+pub async fn voice_task(mut packets: mpsc::Receiver<Vec<u8>>, frames: VoiceFrameSink) {
+    let mut users: HashMap<u32, UserStream> = HashMap::new();
+
+    let mut tick = tokio::time::interval(Duration::from_millis(10));
+
+    loop {
+        tokio::select! {
+            packet = packets.recv() => {
+                let Some(packet) = packet else {
+                    break;
+                };
+
+                // Nobody is listening, don't bother decoding.
+                if frames.receiver_count() == 0 {
+                    continue;
+                }
+
+                let packet = match VoicePacket::parse(&packet) {
+                    Ok(packet) => packet,
+                    Err(e) => {
+                        debug!("Dropping unparseable voice packet: {:?}", e);
+                        continue;
+                    }
+                };
+
+                trace!(target: "mumblebot::net::voice",
+                    "Voice from session {} (target {}, seq {}, position {:?})",
+                    packet.session,
+                    packet.target,
+                    packet.seq_nr,
+                    packet.position
+                );
+
+                let stream = match users.entry(packet.session) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => match UserStream::new(packet.session) {
+                        Ok(stream) => entry.insert(stream),
+                        Err(e) => {
+                            debug!("Failed to create decoder for session {}: {:?}", packet.session, e);
+                            continue;
+                        }
+                    },
+                };
+
+                stream.push(packet);
+            }
+            _ = tick.tick() => {
+                users.retain(|_, stream| stream.last_activity.elapsed() < USER_IDLE_TIMEOUT);
+            }
+        }
+
+        for stream in users.values_mut() {
+            match stream.release() {
+                Ok(decoded) => {
+                    for frame in decoded {
+                        // An error only means there are no subscribers right now.
+                        let _ = frames.send(frame);
+                    }
+                }
+                Err(e) => {
+                    debug!(
+                        "Failed to decode voice of session {}: {:?}",
+                        stream.session, e
+                    );
+                    stream.jitter.clear();
+                    stream.next_seq = None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    #[test]
+    fn corpus_does_not_panic() {
+        let corpus: &[&[u8]] = &[
+            &[],
+            &[0x00],
+            &[0x00, 0xFF, 0xFF],
+            &[0x20],
+            &[0x80],
+            &[0x80, 0x01],
+            &[0x80, 0x01, 0x02],
+            &[0x80, 0x01, 0x02, 0x9F, 0xFF],
+            &[0x80, 0xF4, 0xFF],
+            &[0x80, 0xF8, 0xF8, 0xF8],
+            &[0x80, 0xFC, 0xFD, 0x05, 0x01, 0x02],
+            &[0x9F, 0x01, 0x02, 0x01, 0x00, 0x01, 0x02, 0x03],
+            // A sequence number of u64::MAX, then of 1, with a one-byte Opus frame.
+            &[
+                0x80, 0x01, 0xF4, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01, 0xF8,
+            ],
+            &[0x80, 0x01, 0x01, 0x01, 0xF8],
+        ];
+
+        let mut stream = UserStream::new(1).unwrap();
+        for packet in corpus {
+            if let Ok(packet) = VoicePacket::parse(packet) {
+                stream.push(packet);
+                stream.flush = true;
+                let _ = stream.release();
+            }
+        }
+    }
+
+    #[test]
+    fn random_packets_do_not_panic() {
+        let mut rng = StdRng::seed_from_u64(7);
+
+        for _ in 0..20_000 {
+            let len = rng.random_range(0..64usize);
+            let mut packet: Vec<u8> = (0..len).map(|_| rng.random()).collect();
+
+            // Bias towards the interesting header bytes.
+            if let Some(header) = packet.first_mut() {
+                *header = match rng.random_range(0..3) {
+                    0 => PROTOBUF_TYPE_AUDIO,
+                    1 => (UDP_TYPE_OPUS << 5) | (*header & 0x1F),
+                    _ => *header,
+                };
+            }
+
+            let _ = VoicePacket::parse(&packet);
+        }
+    }
+
+    fn packet(session: u32, terminator: bool) -> VoicePacket {
+        VoicePacket {
+            target: 0,
+            session,
+            seq_nr: 0,
+            payload: vec![],
+            terminator,
+            position: None,
+        }
+    }
+
+    #[test]
+    fn speakers_stop_talking_on_terminator_or_timeout() {
+        let mut speakers = Speakers::default();
+        assert!(!speakers.anyone_talking(|_| true));
+
+        speakers.heard(&packet(1, false));
+        speakers.heard(&packet(2, false));
+        assert!(speakers.anyone_talking(|_| true));
+        assert!(speakers.anyone_talking(|session| session == 2));
+        assert!(!speakers.anyone_talking(|session| session == 3));
+
+        speakers.heard(&packet(2, true));
+        assert!(!speakers.anyone_talking(|session| session == 2));
+        assert!(speakers.anyone_talking(|session| session == 1));
+
+        // Gone quiet without a terminator.
+        speakers
+            .last_heard
+            .insert(1, Instant::now() - TALKING_TIMEOUT);
+        assert!(!speakers.anyone_talking(|_| true));
+        assert!(speakers.last_heard.is_empty());
+    }
+
+    #[test]
+    fn legacy_packet_parses() {
+        // Opus length 3, but only one byte of payload
+        assert!(VoicePacket::parse(&[0x80, 0x05, 0x02, 0x03, 0xAA]).is_err());
+
+        let parsed = VoicePacket::parse(&[0x81, 0x05, 0x02, 0x02, 0xAA, 0xBB]).unwrap();
+        assert_eq!(parsed.target, 1);
+        assert_eq!(parsed.session, 5);
+        assert_eq!(parsed.seq_nr, 2);
+        assert_eq!(parsed.payload, [0xAA, 0xBB]);
+        assert!(!parsed.terminator);
+        assert!(parsed.position.is_none());
+    }
+
+    #[test]
+    fn terminators_are_flagged() {
+        let legacy = encode_voice_packet(VoiceFormat::Legacy, 0, 7, &[], true);
+        assert_eq!(legacy, [0x80, 0x07, 0xA0, 0x00]);
+
+        let protobuf = encode_voice_packet(VoiceFormat::Protobuf, 0, 7, &[], true);
+        assert_eq!(protobuf[0], PROTOBUF_TYPE_AUDIO);
+        assert!(
+            mumble_udp::Audio::decode(&protobuf[1..])
+                .unwrap()
+                .is_terminator
+        );
+    }
+}
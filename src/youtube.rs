@@ -1,19 +1,57 @@
 use std::fmt::Display;
 use std::process::Stdio;
+use std::time::Duration;
 
+use serde::Deserialize;
 use tokio::io::AsyncReadExt;
 use tokio::process::Command;
 use tokio::sync::mpsc::Sender;
 use tokio_util::sync::CancellationToken;
 
-pub async fn get_title(url: impl AsRef<str> + Display) -> anyhow::Result<String> {
-    let pipe = format!("yt-dlp --no-download --dump-json '{}' | jq -r .title", url);
+use crate::types::{Song, SongType};
 
-    let child = Command::new("/bin/sh").arg("-c").arg(pipe).output().await?;
+/**
+ * What yt-dlp tells us about a video, as far as we use it.
+ */
+#[derive(Debug, Deserialize)]
+struct VideoInfo {
+    title: String,
+    /// Length in seconds, unless it is a live stream.
+    duration: Option<f64>,
+    thumbnail: Option<String>,
+}
+
+async fn get_info(url: impl AsRef<str> + Display) -> anyhow::Result<VideoInfo> {
+    let child = Command::new("yt-dlp")
+        .arg("--no-download")
+        .arg("--dump-json")
+        .arg(url.as_ref())
+        .output()
+        .await?;
+
+    if !child.status.success() {
+        anyhow::bail!(
+            "yt-dlp failed for {}: {}",
+            url,
+            String::from_utf8_lossy(&child.stderr).trim()
+        );
+    }
+
+    Ok(serde_json::from_slice(&child.stdout)?)
+}
 
-    let title = String::from_utf8(child.stdout)?;
+pub async fn get_song(url: String) -> anyhow::Result<Song> {
+    let info = get_info(&url).await?;
 
-    Ok(title)
+    Ok(Song {
+        name: info.title,
+        id: url,
+        song_type: SongType::YouTube,
+        duration: info
+            .duration
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok()),
+        image_url: info.thumbnail,
+    })
 }
 
 pub async fn stream_url(